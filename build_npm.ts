@@ -10,7 +10,7 @@ Deno.copyFileSync("js/eszip_wasm_bg.wasm", "npm/esm/eszip_wasm_bg.wasm");
 Deno.copyFileSync("js/eszip_wasm_bg.wasm", "npm/script/eszip_wasm_bg.wasm");
 
 await build({
-  entryPoints: ["./js/mod.ts"],
+  entryPoints: ["./js/mod.ts", { name: "./node", path: "./js/node.ts" }],
   outDir: "./npm",
   shims: {
     deno: true,
@@ -1,9 +1,12 @@
-import { build, Parser } from "./mod.ts";
+import { build, buildFromSources, buildToStream, Parser } from "./mod.ts";
 import {
   assert,
   assertEquals,
   assertRejects,
 } from "jsr:@std/assert@0.223";
+import { Buffer } from "node:buffer";
+import { Readable } from "node:stream";
+import { parseBuffer, parseReadable } from "./node.ts";
 
 Deno.test("roundtrip build + parse", async () => {
   const eszip = await build([
@@ -47,6 +50,10 @@ Deno.test("roundtrip build + parse", async () => {
     "https://example.com/mod.ts",
   ]);
 
+  assertEquals(parser.getRedirects(), {});
+  assertEquals(parser.getEntryKind("https://example.com/mod.ts"), "module");
+  assertEquals(parser.getEntryKind("https://example.com/missing.ts"), null);
+
   await parser.load();
   const mod = await parser.getModuleSource("https://example.com/mod.ts");
   assertEquals(mod, 'import "https://example.com/a.ts";\n');
@@ -54,6 +61,132 @@ Deno.test("roundtrip build + parse", async () => {
   assertEquals(a, "export const a = 1;\n");
 });
 
+Deno.test("build to stream", async () => {
+  const loader = (specifier: string) =>
+    Promise.resolve({
+      kind: "module" as const,
+      specifier,
+      headers: {
+        "content-type": "application/typescript",
+      },
+      content: "export const a: number = 1;",
+    });
+  const chunks: Uint8Array[] = [];
+  const writable = new WritableStream<Uint8Array>({
+    write(chunk) {
+      chunks.push(chunk);
+    },
+  });
+  await buildToStream(["https://example.com/mod.ts"], writable, loader);
+
+  const eszip = new Uint8Array(await new Blob(chunks).arrayBuffer());
+  assertEquals(eszip, await build(["https://example.com/mod.ts"], loader));
+});
+
+Deno.test("build from sources", async () => {
+  const eszip = await buildFromSources({
+    "file:///main.js": {
+      kind: "javascript",
+      source: 'import "./data.json";',
+      sourceMap: "{}",
+    },
+    "file:///data.json": {
+      kind: "json",
+      source: new TextEncoder().encode("{}"),
+    },
+  }, JSON.stringify({ root_packages: {}, packages: [] }));
+
+  const parser = await Parser.createInstance();
+  const specifiers = await parser.parseBytes(eszip);
+  assertEquals(specifiers, ["file:///main.js", "file:///data.json"]);
+  await parser.load();
+  assertEquals(
+    await parser.getModuleSource("file:///main.js"),
+    'import "./data.json";',
+  );
+  assertEquals(await parser.getModuleSourceMap("file:///main.js"), "{}");
+  assertEquals(await parser.getModuleSource("file:///data.json"), "{}");
+});
+
+Deno.test("checksum introspection", async () => {
+  const eszip = await build(
+    ["https://example.com/mod.ts"],
+    (specifier: string) =>
+      Promise.resolve({
+        kind: "module" as const,
+        specifier,
+        headers: {
+          "content-type": "application/typescript",
+        },
+        content: "export const a: number = 1;",
+      }),
+  );
+  const parser = await Parser.createInstance();
+  await parser.parseBytes(eszip);
+  assertEquals(parser.isChecksumed(), false);
+  assertEquals(parser.shouldBeChecksumed(), false);
+  assertEquals(parser.checksum(), "noChecksum");
+});
+
+Deno.test("parse from a default reader", async () => {
+  const eszip = await build(
+    ["https://example.com/mod.ts"],
+    (specifier: string) =>
+      Promise.resolve({
+        kind: "module" as const,
+        specifier,
+        headers: {
+          "content-type": "application/javascript",
+        },
+        content: "export const a = 1;",
+      }),
+  );
+  // split the eszip into small chunks that do not line up with the reads of
+  // the parser
+  const stream = new ReadableStream<Uint8Array>({
+    start(controller) {
+      for (let i = 0; i < eszip.length; i += 7) {
+        controller.enqueue(eszip.slice(i, i + 7));
+      }
+      controller.close();
+    },
+  });
+  const parser = await Parser.createInstance();
+  const specifiers = await parser.parseDefaultReader(stream.getReader());
+  assertEquals(specifiers, ["https://example.com/mod.ts"]);
+  await parser.load();
+  const source = await parser.getModuleSourceBytes(
+    "https://example.com/mod.ts",
+  );
+  assert(source instanceof Uint8Array);
+  assertEquals(
+    new TextDecoder().decode(source),
+    await parser.getModuleSource("https://example.com/mod.ts"),
+  );
+});
+
+Deno.test("parse node buffers and streams", async () => {
+  const eszip = await buildFromSources({
+    "file:///main.js": { kind: "javascript", source: "export {};" },
+  });
+
+  const parser = await Parser.createInstance();
+  const buffer = Buffer.concat([Buffer.alloc(3), eszip]).subarray(3);
+  assertEquals(await parseBuffer(parser, buffer), ["file:///main.js"]);
+  await parser.load();
+  assertEquals(await parser.getModuleSource("file:///main.js"), "export {};");
+
+  const chunks = [];
+  for (let i = 0; i < eszip.length; i += 5) {
+    chunks.push(Buffer.from(eszip.subarray(i, i + 5)));
+  }
+  const streamed = await Parser.createInstance();
+  assertEquals(
+    await parseReadable(streamed, Readable.from(chunks)),
+    ["file:///main.js"],
+  );
+});
+
 Deno.test("build default loader", async () => {
   const eszip = await build(["https://deno.land/std@0.123.0/fs/mod.ts"]);
   assert(eszip instanceof Uint8Array);
@@ -31,30 +31,81 @@ export async function build(
   importMapUrl?: string,
 ): Promise<Uint8Array> {
   const { build } = await instantiate({ url: options.wasmURL });
-  return build(
-    roots,
-    (specifier: string, options: {
-      isDynamic: boolean;
-      cacheSetting: CacheSetting;
-      checksum: string | undefined;
-    }) => {
-      return loader(
-        specifier,
-        options.isDynamic,
-        options.cacheSetting,
-        options.checksum,
-      ).then((result) => {
-        if (result?.kind === "module") {
-          if (typeof result.content === "string") {
-            result.content = encoder.encode(result.content);
-          }
-          // need to convert to an array for serde_wasm_bindgen to work
-          // deno-lint-ignore no-explicit-any
-          (result as any).content = Array.from(result.content);
+  return build(roots, wrapLoader(loader), importMapUrl);
+}
+
+/**
+ * Like {@link build}, but writes the eszip into `writable` chunk by chunk
+ * instead of returning it as a single `Uint8Array`. The stream is closed once
+ * the whole eszip is written.
+ */
+export async function buildToStream(
+  roots: string[],
+  writable: WritableStream<Uint8Array>,
+  loader: Loader["load"] = createCache().load,
+  importMapUrl?: string,
+): Promise<void> {
+  const { buildToStream } = await instantiate({ url: options.wasmURL });
+  return buildToStream(roots, wrapLoader(loader), importMapUrl, writable);
+}
+
+/** A module passed to {@link buildFromSources}. */
+export interface ModuleSource {
+  kind:
+    | "javascript"
+    | "json"
+    | "jsonc"
+    | "opaquedata"
+    | "wasm"
+    | "declaration"
+    | "bytes"
+    | "text";
+  source: string | Uint8Array;
+  sourceMap?: string;
+}
+
+/**
+ * Build an eszip from modules that are already transpiled, e.g. the outputs of
+ * a bundler, without a loader. The modules are stored in the order of their
+ * keys. `npmSnapshotJson` is a serialized npm resolution snapshot of the npm
+ * packages the modules import, if any.
+ */
+export async function buildFromSources(
+  modules: Record<string, ModuleSource>,
+  npmSnapshotJson?: string,
+): Promise<Uint8Array> {
+  const { buildFromSources } = await instantiate({ url: options.wasmURL });
+  const entries = Object.entries(modules).map(([specifier, module]) => {
+    const source = typeof module.source === "string"
+      ? encoder.encode(module.source)
+      : module.source;
+    // need to convert to an array for serde_wasm_bindgen to work
+    return [specifier, { ...module, source: Array.from(source) }];
+  });
+  return buildFromSources(entries, npmSnapshotJson);
+}
+
+function wrapLoader(loader: Loader["load"]) {
+  return (specifier: string, options: {
+    isDynamic: boolean;
+    cacheSetting: CacheSetting;
+    checksum: string | undefined;
+  }) => {
+    return loader(
+      specifier,
+      options.isDynamic,
+      options.cacheSetting,
+      options.checksum,
+    ).then((result) => {
+      if (result?.kind === "module") {
+        if (typeof result.content === "string") {
+          result.content = encoder.encode(result.content);
         }
-        return result;
-      }).catch((err) => Promise.reject(String(err)));
-    },
-    importMapUrl,
-  );
+        // need to convert to an array for serde_wasm_bindgen to work
+        // deno-lint-ignore no-explicit-any
+        (result as any).content = Array.from(result.content);
+      }
+      return result;
+    }).catch((err) => Promise.reject(String(err)));
+  };
 }
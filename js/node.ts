@@ -0,0 +1,35 @@
+import type { Buffer } from "node:buffer";
+import { Readable } from "node:stream";
+import type { Parser } from "./mod.ts";
+export * from "./mod.ts";
+
+/**
+ * Parse an eszip from a Node.js `Buffer` with `parser`, without copying the
+ * buffer in JavaScript. Resolves to the specifiers of the modules.
+ */
+export function parseBuffer(
+  parser: Parser,
+  buffer: Buffer | Uint8Array,
+): Promise<string[]> {
+  const bytes = new Uint8Array(
+    buffer.buffer,
+    buffer.byteOffset,
+    buffer.byteLength,
+  );
+  return parser.parseBytes(bytes);
+}
+
+/**
+ * Parse an eszip from a Node.js `Readable` stream of buffers with `parser`,
+ * e.g. a `fs.createReadStream`. Streams with an encoding, which produce
+ * strings, are not supported. Resolves to the specifiers of the modules.
+ */
+export function parseReadable(
+  parser: Parser,
+  readable: Readable,
+): Promise<string[]> {
+  // Node.js streams do not support BYOB readers, so read through a default
+  // reader, which the Node.js build of the wasm module supports too.
+  const stream = Readable.toWeb(readable) as ReadableStream<Uint8Array>;
+  return parser.parseDefaultReader(stream.getReader());
+}
@@ -14,36 +14,42 @@ use deno_graph::BuildOptions;
 use deno_graph::GraphKind;
 use deno_graph::ModuleGraph;
 use deno_graph::ModuleSpecifier;
+use deno_npm::resolution::SerializedNpmResolutionSnapshot;
 use eszip::v2::Url;
 use eszip::ModuleKind;
 use futures::io::AsyncRead;
 use futures::io::BufReader;
+use futures::io::Cursor;
 use import_map::ImportMap;
 use js_sys::Promise;
 use js_sys::TypeError;
 use js_sys::Uint8Array;
+use serde::Deserialize;
 use serde::Serialize;
 use std::cell::RefCell;
 use std::future::Future;
 use std::io::Error;
-use std::io::ErrorKind;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
+#[cfg(feature = "web")]
 use web_sys::ReadableStreamByobReader;
+use web_sys::ReadableStreamDefaultReader;
+use web_sys::WritableStream;
 
 /// A `Stream` holds a Byob reader and the
 /// future of the current `reader.read` operation.
+#[cfg(feature = "web")]
 struct Stream {
   inner: Option<ReadableStreamByobReader>,
   fut: Option<JsFuture>,
 }
 
+#[cfg(feature = "web")]
 impl Stream {
   fn new(inner: ReadableStreamByobReader) -> Self {
     Self {
@@ -53,9 +59,81 @@ impl Stream {
   }
 }
 
+/// A `DefaultStream` holds a default reader, the
+/// future of the current `reader.read` operation
+/// and the part of the last chunk that was not
+/// read yet.
+///
+/// Default readers decide on the size of the
+/// chunks, so chunks that are larger than the
+/// read buffer are kept for the following reads.
+struct DefaultStream {
+  inner: Option<ReadableStreamDefaultReader>,
+  fut: Option<JsFuture>,
+  chunk: Option<(Uint8Array, u32)>,
+}
+
+impl DefaultStream {
+  fn new(inner: ReadableStreamDefaultReader) -> Self {
+    Self {
+      inner: Some(inner),
+      fut: None,
+      chunk: None,
+    }
+  }
+
+  fn poll_read(
+    &mut self,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<Result<usize, Error>> {
+    loop {
+      // Serve the rest of the last chunk first.
+      if let Some((chunk, offset)) = self.chunk.take() {
+        let length = (chunk.length() - offset).min(buf.len() as u32);
+        chunk
+          .subarray(offset, offset + length)
+          .copy_to(&mut buf[..length as usize]);
+        if offset + length < chunk.length() {
+          self.chunk = Some((chunk, offset + length));
+        }
+        return Poll::Ready(Ok(length as usize));
+      }
+
+      let fut = match self.fut.as_mut() {
+        Some(fut) => fut,
+        None => match &self.inner {
+          Some(reader) => self.fut.insert(JsFuture::from(reader.read())),
+          None => return Poll::Ready(Ok(0)),
+        },
+      };
+      let result = match Pin::new(fut).poll(cx) {
+        Poll::Ready(result) => result,
+        Poll::Pending => return Poll::Pending,
+      };
+      // Clear slot for next `read()`.
+      self.fut = None;
+
+      let result = result.map_err(js_error)?.unchecked_into::<ReadResult>();
+      if result.is_done() {
+        // Drop the readable stream.
+        self.inner = None;
+        return Poll::Ready(Ok(0));
+      }
+      let chunk = result.value().ok_or_else(|| {
+        Error::other("the stream must produce Uint8Array chunks")
+      })?;
+      // Empty chunks would otherwise look like the end of the stream.
+      if chunk.length() > 0 {
+        self.chunk = Some((chunk, 0));
+      }
+    }
+  }
+}
+
 #[wasm_bindgen]
 extern "C" {
-  /// Result of a read on BYOB reader.
+  /// Result of a read on a BYOB or default reader.
   /// { value: Uint8Array, done: boolean }
   pub type ReadResult;
   #[wasm_bindgen(method, getter, js_name = done)]
@@ -66,14 +144,24 @@ extern "C" {
 }
 
 /// A `ParserStream` is a wrapper around
-/// Byob stream that also supports reading
-/// through in-memory buffers.
+/// Byob and default streams that also supports
+/// reading through in-memory buffers.
 ///
 /// We need this because `#[wasm_bindgen]`
 /// structs cannot have type parameters.
 enum ParserStream {
+  #[cfg(feature = "web")]
   Byob(Stream),
-  Buffer(Vec<u8>),
+  Default(DefaultStream),
+  Buffer(Cursor<Vec<u8>>),
+}
+
+fn js_error(e: JsValue) -> Error {
+  Error::other(
+    js_sys::Object::try_from(&e)
+      .map(|e| e.to_string().as_string().unwrap_throw())
+      .unwrap_or("Unknown error".to_string()),
+  )
 }
 
 impl AsyncRead for ParserStream {
@@ -83,6 +171,7 @@ impl AsyncRead for ParserStream {
     buf: &mut [u8],
   ) -> Poll<Result<usize, Error>> {
     match *self {
+      #[cfg(feature = "web")]
       ParserStream::Byob(ref mut stream) => {
         // If we have a pending future, poll it.
         // otherwise, schedule a new one.
@@ -127,22 +216,12 @@ impl AsyncRead for ParserStream {
               }
             }
           }
-          Err(e) => Poll::Ready(Err(Error::new(
-            ErrorKind::Other,
-            js_sys::Object::try_from(&e)
-              .map(|e| e.to_string().as_string().unwrap_throw())
-              .unwrap_or("Unknown error".to_string()),
-          ))),
+          Err(e) => Poll::Ready(Err(js_error(e))),
         }
       }
+      ParserStream::Default(ref mut stream) => stream.poll_read(cx, buf),
       ParserStream::Buffer(ref mut buffer) => {
-        // Put the requested bytes into the buffer and
-        // assign the remaining bytes back into the sink.
-        let amt = std::cmp::min(buffer.len(), buf.len());
-        let (a, b) = buffer.split_at(amt);
-        buf[..amt].copy_from_slice(a);
-        *buffer = b.to_vec();
-        Poll::Ready(Ok(amt))
+        Pin::new(buffer).poll_read(cx, buf)
       }
     }
   }
@@ -157,6 +236,12 @@ pub struct Parser {
   parser: Rc<RefCell<Option<ParseResult<ParserStream>>>>,
 }
 
+impl Default for Parser {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 #[wasm_bindgen]
 impl Parser {
   #[wasm_bindgen(constructor)]
@@ -168,15 +253,32 @@ impl Parser {
   }
 
   /// Parse from a BYOB readable stream.
+  #[cfg(feature = "web")]
   pub fn parse(&self, stream: ReadableStreamByobReader) -> Promise {
     let reader = BufReader::new(ParserStream::Byob(Stream::new(stream)));
     self.parse_reader(reader)
   }
 
+  /// Parse from the default reader of a readable stream of `Uint8Array`
+  /// chunks, for runtimes that do not support BYOB readers.
+  #[wasm_bindgen(js_name = parseDefaultReader)]
+  pub fn parse_default_reader(
+    &self,
+    stream: ReadableStreamDefaultReader,
+  ) -> Promise {
+    let reader =
+      BufReader::new(ParserStream::Default(DefaultStream::new(stream)));
+    self.parse_reader(reader)
+  }
+
   /// Parse from an in-memory buffer.
+  ///
+  /// JS buffers can not be shared with wasm memory, so the buffer is copied
+  /// into it when it is passed in, and the sources and source maps are copied
+  /// out of that copy as they are parsed, like for the other readers.
   #[wasm_bindgen(js_name = parseBytes)]
   pub fn parse_bytes(&self, buffer: Vec<u8>) -> Promise {
-    let reader = BufReader::new(ParserStream::Buffer(buffer));
+    let reader = BufReader::new(ParserStream::Buffer(Cursor::new(buffer)));
     self.parse_reader(reader)
   }
 
@@ -198,6 +300,9 @@ impl Parser {
   }
 
   /// Load module sources.
+  // The loader future lives inside the parser, so it has to stay borrowed
+  // while it is being polled.
+  #[allow(clippy::await_holding_refcell_ref)]
   pub fn load(&mut self) -> Promise {
     let parser = Rc::clone(&self.parser);
 
@@ -209,47 +314,98 @@ impl Parser {
     })
   }
 
+  /// Whether the sources of the parsed eszip were verified against their
+  /// checksums, see `EszipV2::is_checksumed`.
+  #[wasm_bindgen(js_name = isChecksumed)]
+  pub fn is_checksumed(&self) -> bool {
+    self.with_eszip(|eszip| eszip.is_checksumed())
+  }
+
+  /// Whether the parsed eszip is configured with a checksum, even one that
+  /// this version does not know, see `EszipV2::should_be_checksumed`.
+  #[wasm_bindgen(js_name = shouldBeChecksumed)]
+  pub fn should_be_checksumed(&self) -> bool {
+    self.with_eszip(|eszip| eszip.should_be_checksumed())
+  }
+
+  /// The name of the hash function the parsed eszip is checksumed with, i.e.
+  /// `"noChecksum"`, `"sha256"` or `"xxHash3"`, or `null` if it is not known
+  /// to this version.
+  pub fn checksum(&self) -> JsValue {
+    self.with_eszip(|eszip| match eszip.checksum() {
+      Some(checksum) => serde_wasm_bindgen::to_value(&checksum).unwrap(),
+      None => JsValue::NULL,
+    })
+  }
+
+  /// Get the redirects of the parsed eszip, as an object from specifier to
+  /// target.
+  #[wasm_bindgen(js_name = getRedirects)]
+  pub fn get_redirects(&self) -> JsValue {
+    self.with_eszip(|eszip| {
+      let serializer =
+        serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
+      eszip.redirects().serialize(&serializer).unwrap()
+    })
+  }
+
+  /// Get the kind of the entry with the given specifier, i.e. `"module"`,
+  /// `"redirect"` or `"npm"`, or `null` if there is no such entry. Redirects
+  /// are not followed.
+  #[wasm_bindgen(js_name = getEntryKind)]
+  pub fn get_entry_kind(&self, specifier: String) -> JsValue {
+    self.with_eszip(|eszip| match eszip.entry_kind(&specifier) {
+      Some(kind) => serde_wasm_bindgen::to_value(&kind).unwrap(),
+      None => JsValue::NULL,
+    })
+  }
+
+  fn with_eszip<T>(&self, f: impl FnOnce(&eszip::EszipV2) -> T) -> T {
+    let p = self.parser.borrow();
+    let (eszip, _) = p.as_ref().expect_throw("eszip not parsed yet");
+    f(eszip)
+  }
+
   /// Get a module source.
   #[wasm_bindgen(js_name = getModuleSource)]
   pub fn get_module_source(&self, specifier: String) -> Promise {
     let parser = Rc::clone(&self.parser);
 
     wasm_bindgen_futures::future_to_promise(async move {
-      let p = parser.borrow();
-      let (eszip, _) = p.as_ref().unwrap();
-      let module = eszip
-        .get_module(&specifier)
-        .or_else(|| eszip.get_import_map(&specifier))
-        .ok_or(TypeError::new(&format!("module '{}' not found", specifier)))?;
-
-      // Drop the borrow for the loader
-      // to mutably borrow.
-      drop(p);
-      let source = module.source().await.ok_or(TypeError::new(&format!(
-        "source for '{}' already taken",
-        specifier
-      )))?;
+      let source = module_source(&parser, &specifier).await?;
       let source = std::str::from_utf8(&source).unwrap();
       Ok(source.to_string().into())
     })
   }
 
+  /// Get a module source as a `Uint8Array`, without decoding it as UTF-8.
+  /// This can be used to read opaque data entries.
+  #[wasm_bindgen(js_name = getModuleSourceBytes)]
+  pub fn get_module_source_bytes(&self, specifier: String) -> Promise {
+    let parser = Rc::clone(&self.parser);
+
+    wasm_bindgen_futures::future_to_promise(async move {
+      let source = module_source(&parser, &specifier).await?;
+      Ok(Uint8Array::from(&source[..]).into())
+    })
+  }
+
   /// Get a module sourcemap.
   #[wasm_bindgen(js_name = getModuleSourceMap)]
   pub fn get_module_source_map(&self, specifier: String) -> Promise {
     let parser = Rc::clone(&self.parser);
 
     wasm_bindgen_futures::future_to_promise(async move {
-      let p = parser.borrow();
-      let (eszip, _) = p.as_ref().unwrap();
-      let module = eszip
-        .get_module(&specifier)
-        .or_else(|| eszip.get_import_map(&specifier))
-        .ok_or(TypeError::new(&format!("module '{}' not found", specifier)))?;
-
-      // Drop the borrow for the loader
+      // Drop the borrow before awaiting for the loader
       // to mutably borrow.
-      drop(p);
+      let module = {
+        let p = parser.borrow();
+        let (eszip, _) = p.as_ref().unwrap();
+        eszip
+          .get_module(&specifier)
+          .or_else(|| eszip.get_import_map(&specifier))
+          .ok_or(TypeError::new(&format!("module '{}' not found", specifier)))?
+      };
       match module.source_map().await {
         Some(source_map) => {
           let source_map = std::str::from_utf8(&source_map).unwrap();
@@ -261,7 +417,34 @@ impl Parser {
   }
 }
 
+async fn module_source(
+  parser: &RefCell<Option<ParseResult<ParserStream>>>,
+  specifier: &str,
+) -> Result<std::sync::Arc<[u8]>, JsValue> {
+  // Drop the borrow before awaiting for the loader
+  // to mutably borrow.
+  let module = {
+    let p = parser.borrow();
+    let (eszip, _) = p.as_ref().unwrap();
+    eszip
+      .get_module(specifier)
+      .or_else(|| eszip.get_import_map(specifier))
+      .ok_or(TypeError::new(&format!("module '{}' not found", specifier)))?
+  };
+  let source = module.source().await.ok_or(TypeError::new(&format!(
+    "source for '{}' already taken",
+    specifier
+  )))?;
+  Ok(source)
+}
+
+/// Size of the chunks written by [`build_eszip_to_stream`].
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Serialize a module graph into eszip.
+///
+/// The archive is serialized in wasm memory, and copied into the returned
+/// `Uint8Array`; see [`build_eszip_to_stream`] to hand it over in chunks.
 #[wasm_bindgen(js_name = build)]
 pub async fn build_eszip(
   roots: JsValue,
@@ -269,6 +452,81 @@ pub async fn build_eszip(
   import_map_url: JsValue,
 ) -> Result<Uint8Array, JsValue> {
   std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+  let eszip = build_eszip_v2(roots, loader, import_map_url).await?;
+  Ok(Uint8Array::from(eszip.into_bytes().as_slice()))
+}
+
+/// Serialize a module graph into eszip, writing the archive into a
+/// `WritableStream` chunk by chunk instead of returning it as a single
+/// `Uint8Array`. The stream is closed once the whole archive is written.
+#[wasm_bindgen(js_name = buildToStream)]
+pub async fn build_eszip_to_stream(
+  roots: JsValue,
+  loader: js_sys::Function,
+  import_map_url: JsValue,
+  stream: WritableStream,
+) -> Result<(), JsValue> {
+  std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+  let eszip = build_eszip_v2(roots, loader, import_map_url).await?;
+  let bytes = eszip.into_bytes();
+  let writer = stream.get_writer()?;
+  for chunk in bytes.chunks(STREAM_CHUNK_SIZE) {
+    // Wait for the sink to have room, to not queue the whole archive.
+    JsFuture::from(writer.ready()).await?;
+    JsFuture::from(writer.write_with_chunk(&Uint8Array::from(chunk))).await?;
+  }
+  JsFuture::from(writer.close()).await?;
+  Ok(())
+}
+
+/// A module passed to [`build_eszip_from_sources`].
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsModuleSource {
+  kind: ModuleKind,
+  source: Vec<u8>,
+  source_map: Option<String>,
+}
+
+/// Serialize modules that are already transpiled, e.g. the outputs of a
+/// bundler, into eszip, without building a module graph. `modules` are the
+/// `[specifier, module]` entries of the modules, in the order in which they
+/// are stored.
+#[wasm_bindgen(js_name = buildFromSources)]
+pub fn build_eszip_from_sources(
+  modules: JsValue,
+  npm_snapshot_json: Option<String>,
+) -> Result<Uint8Array, JsValue> {
+  std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+  let modules: Vec<(String, JsModuleSource)> =
+    serde_wasm_bindgen::from_value(modules)
+      .map_err(|e| js_sys::Error::new(&e.to_string()))?;
+  let mut eszip = eszip::EszipV2::default();
+  for (specifier, module) in modules {
+    eszip.add_module(
+      module.kind,
+      specifier,
+      module.source,
+      module.source_map.unwrap_or_default().into_bytes(),
+    );
+  }
+  if let Some(npm_snapshot_json) = npm_snapshot_json {
+    let snapshot: SerializedNpmResolutionSnapshot =
+      serde_json::from_str(&npm_snapshot_json)
+        .map_err(|e| js_sys::Error::new(&e.to_string()))?;
+    let snapshot = snapshot
+      .into_valid()
+      .map_err(|e| js_sys::Error::new(&e.to_string()))?;
+    eszip.add_npm_snapshot(snapshot);
+  }
+  Ok(Uint8Array::from(eszip.into_bytes().as_slice()))
+}
+
+async fn build_eszip_v2(
+  roots: JsValue,
+  loader: js_sys::Function,
+  import_map_url: JsValue,
+) -> Result<eszip::EszipV2, JsValue> {
   let roots: Vec<deno_graph::ModuleSpecifier> =
     serde_wasm_bindgen::from_value(roots)
       .map_err(|e| js_sys::Error::new(&e.to_string()))?;
@@ -300,7 +558,7 @@ pub async fn build_eszip(
       } => {
         let import_map = import_map::parse_from_json_with_options(
           specifier.clone(),
-          &String::from_utf8(content.to_vec()).unwrap(),
+          std::str::from_utf8(&content).unwrap(),
           import_map::ImportMapOptions {
             address_hook: None,
             // always do this for simplicity
@@ -347,6 +605,7 @@ pub async fn build_eszip(
     emit_options: Default::default(),
     relative_file_base: None,
     npm_packages: None,
+    config: Default::default(),
   })
   .map_err(|e| js_sys::Error::new(&e.to_string()))?;
   if let Some((import_map_specifier, import_map_content)) =
@@ -355,10 +614,10 @@ pub async fn build_eszip(
     eszip.add_import_map(
       ModuleKind::Json,
       import_map_specifier.to_string(),
-      Arc::from(import_map_content),
+      import_map_content,
     )
   }
-  Ok(Uint8Array::from(eszip.into_bytes().as_slice()))
+  Ok(eszip)
 }
 
 // Taken from deno_graph
@@ -0,0 +1,151 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Locating eszip archives embedded in executables.
+
+use std::io::SeekFrom;
+
+use futures::io::AsyncRead;
+use futures::io::AsyncReadExt;
+use futures::io::AsyncSeek;
+use futures::io::AsyncSeekExt;
+
+use crate::error::ParseError;
+use crate::EszipV2;
+
+/// Magic of the trailer that `deno compile` appends to standalone binaries.
+const TRAILER_MAGIC: &[u8; 8] = b"d3n0l4nd";
+/// Magic (8) | eszip position (8) | metadata position (8) |
+/// npm vfs position (8) | npm files position (8)
+const TRAILER_SIZE: usize = 40;
+
+/// Locate the eszip archive embedded in an executable, like the standalone
+/// binaries produced by `deno compile`, and parse it. This can be used to
+/// audit and debug deployed artifacts.
+///
+/// If the executable ends with a `deno compile` trailer, only the trailer and
+/// the archive at the position recorded in it are read. Otherwise the whole
+/// executable is read and searched for the magic of an eszip v2 archive, and
+/// the last occurrence that parses successfully is used.
+///
+/// To read a file without blocking, pass an async file, or wrap a
+/// [`std::fs::File`] in [`futures::io::AllowStdIo`] on a thread where
+/// blocking is fine.
+pub async fn extract_from_binary<R: AsyncRead + AsyncSeek + Unpin>(
+  mut reader: R,
+) -> Result<EszipV2, ParseError> {
+  let len = reader.seek(SeekFrom::End(0)).await?;
+  if len >= TRAILER_SIZE as u64 {
+    let mut trailer = [0u8; TRAILER_SIZE];
+    reader.seek(SeekFrom::End(-(TRAILER_SIZE as i64))).await?;
+    reader.read_exact(&mut trailer).await?;
+    if let Some(position) = trailer_eszip_position(&trailer) {
+      reader.seek(SeekFrom::Start(position)).await?;
+      let (eszip, loader) =
+        EszipV2::parse(futures::io::BufReader::new(&mut reader)).await?;
+      loader.await?;
+      return Ok(eszip);
+    }
+  }
+
+  let mut bytes = Vec::new();
+  reader.seek(SeekFrom::Start(0)).await?;
+  reader.read_to_end(&mut bytes).await?;
+  search(&bytes).await
+}
+
+async fn search(bytes: &[u8]) -> Result<EszipV2, ParseError> {
+  // The magic is also present in the code of the executable itself, so keep
+  // looking until an occurrence parses.
+  let mut last_err = ParseError::InvalidV2;
+  for position in (0..bytes.len().saturating_sub(8)).rev() {
+    if !EszipV2::has_magic(&bytes[position..]) {
+      continue;
+    }
+    match parse_at(bytes, position).await {
+      Ok(eszip) => return Ok(eszip),
+      Err(err) => last_err = err,
+    }
+  }
+  Err(last_err)
+}
+
+fn trailer_eszip_position(trailer: &[u8; TRAILER_SIZE]) -> Option<u64> {
+  if &trailer[..8] != TRAILER_MAGIC {
+    return None;
+  }
+  Some(u64::from_be_bytes(trailer[8..16].try_into().unwrap()))
+}
+
+async fn parse_at(
+  bytes: &[u8],
+  position: usize,
+) -> Result<EszipV2, ParseError> {
+  let reader = futures::io::BufReader::new(
+    bytes.get(position..).ok_or(ParseError::InvalidV2)?,
+  );
+  let (eszip, loader) = EszipV2::parse(reader).await?;
+  loader.await?;
+  Ok(eszip)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::ModuleKind;
+
+  fn eszip_bytes() -> Vec<u8> {
+    let mut eszip = EszipV2::default();
+    eszip.add_to_front(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"console.log(1);",
+      [],
+    );
+    eszip.add_opaque_data("data".to_string(), Arc::from(*b"data"));
+    eszip.into_bytes()
+  }
+
+  async fn extract(binary: &[u8]) -> Result<EszipV2, ParseError> {
+    extract_from_binary(futures::io::Cursor::new(binary)).await
+  }
+
+  async fn assert_extracted(binary: &[u8]) {
+    let eszip = extract(binary).await.unwrap();
+    assert_eq!(eszip.specifiers(), vec!["file:///main.js", "data"]);
+    let source = eszip
+      .get_module("file:///main.js")
+      .unwrap()
+      .source()
+      .await
+      .unwrap();
+    assert_eq!(&*source, b"console.log(1);");
+  }
+
+  #[tokio::test]
+  async fn extract_with_trailer() {
+    let mut binary = b"\x7fELF ESZIP2.2 ESZIP_V2 code".to_vec();
+    let eszip_pos = binary.len() as u64;
+    binary.extend_from_slice(&eszip_bytes());
+    binary.extend_from_slice(b"metadata");
+    binary.extend_from_slice(TRAILER_MAGIC);
+    binary.extend_from_slice(&eszip_pos.to_be_bytes());
+    binary.extend_from_slice(&[0; 24]);
+    assert_extracted(&binary).await;
+  }
+
+  #[tokio::test]
+  async fn extract_by_searching_magic() {
+    let mut binary = b"\x7fELF ESZIP2.2 ESZIP_V2 code".to_vec();
+    binary.extend_from_slice(&eszip_bytes());
+    binary.extend_from_slice(b"more code ESZIP2.1");
+    assert_extracted(&binary).await;
+  }
+
+  #[tokio::test]
+  async fn extract_without_eszip() {
+    assert!(extract(b"\x7fELF ESZIP2.2 code").await.is_err());
+    assert!(extract(b"").await.is_err());
+  }
+}
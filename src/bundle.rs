@@ -0,0 +1,1154 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Flattening the modules of an eszip into a single ECMAScript module, see
+//! [`to_single_module`].
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use deno_ast::swc::ast::*;
+use deno_ast::swc::common::Span;
+use deno_ast::swc::common::Spanned;
+use deno_ast::swc::common::SyntaxContext;
+use deno_ast::swc::utils::find_pat_ids;
+use deno_ast::swc::visit::Visit;
+use deno_ast::swc::visit::VisitWith;
+use deno_ast::MediaType;
+use deno_ast::ModuleSpecifier;
+use deno_ast::ParseParams;
+use deno_ast::TextChange;
+use import_map::ImportMap;
+use indexmap::IndexMap;
+use indexmap::IndexSet;
+
+use crate::error::BundleError;
+use crate::EszipV2;
+use crate::ModuleKind;
+
+/// The base that the relative specifiers of archives built with
+/// [`crate::FromGraphOptions::relative_file_base`] are resolved against.
+const RELATIVE_BASE: &str = "file:///";
+
+/// Globals that the bundle itself refers to, which the modules must not
+/// shadow.
+const BUNDLE_GLOBALS: [&str; 4] = ["Object", "Promise", "Symbol", "Uint8Array"];
+
+/// Concatenate the module `root` and the modules it imports into a single
+/// ECMAScript module that does not import any of the modules of the archive.
+///
+/// Import specifiers are resolved against the importing module like the
+/// runtime would, with the import map of the archive if it has one. The
+/// modules are concatenated in evaluation order, their top level bindings
+/// renamed where they would collide, and their imports replaced with the
+/// bindings they refer to. Namespace imports become frozen objects with a
+/// getter for each export. The exports of `root` are the exports of the
+/// bundle.
+///
+/// Imports of modules that are not in the archive, like `npm:` and `node:`
+/// specifiers, are kept as imports at the top of the bundle. JSON, text and
+/// bytes modules are inlined as values. Modules that are only imported with a
+/// dynamic `import()` of a string literal are evaluated with the rest of the
+/// bundle, and the `import()` resolves to their namespace. `import.meta` is
+/// left as is and so refers to the bundle. Names re-exported with `export *`
+/// from modules that are not in the archive are only exported by the bundle
+/// if `root` re-exports them.
+pub async fn to_single_module(
+  eszip: &EszipV2,
+  root: &str,
+) -> Result<String, BundleError> {
+  let import_map = match eszip.specifiers().first() {
+    Some(specifier)
+      if eszip.get_import_map(specifier).is_some_and(|module| {
+        matches!(module.kind, ModuleKind::Json | ModuleKind::Jsonc)
+      }) =>
+    {
+      Some(crate::validate::load_import_map(eszip, specifier).await?)
+    }
+    _ => None,
+  };
+  let root = eszip
+    .get_module(root)
+    .ok_or_else(|| BundleError::NotFound(root.to_string()))?;
+
+  let mut bundler = Bundler {
+    eszip,
+    import_map,
+    specifiers: IndexSet::new(),
+    modules: Vec::new(),
+    idents: HashSet::new(),
+    names: BUNDLE_GLOBALS.iter().map(|name| name.to_string()).collect(),
+    externals: IndexMap::new(),
+    namespaces: IndexMap::new(),
+  };
+  bundler.specifiers.insert(root.specifier);
+  while bundler.modules.len() < bundler.specifiers.len() {
+    bundler.load_next().await?;
+  }
+  bundler.emit()
+}
+
+struct Bundler<'a> {
+  eszip: &'a EszipV2,
+  import_map: Option<ImportMap>,
+  /// The specifiers of the bundled modules, by index. The first is the root.
+  specifiers: IndexSet<String>,
+  /// The modules that are loaded so far, by index.
+  modules: Vec<BundledModule>,
+  /// The identifiers that appear in the modules other than as references to
+  /// top level bindings, which the names of the bundle must not shadow.
+  idents: HashSet<String>,
+  /// The top level names of the bundle that are taken.
+  names: HashSet<String>,
+  externals: IndexMap<String, External>,
+  /// The names of the namespace objects of the bundled modules, by index.
+  namespaces: IndexMap<usize, String>,
+}
+
+struct BundledModule {
+  specifier: String,
+  content: Content,
+  /// The names of the top level bindings of the module in the bundle.
+  renames: HashMap<String, String>,
+  /// The name of the default export that is declared by the bundle, for
+  /// `export default` expressions and inlined values.
+  default_name: Option<String>,
+}
+
+enum Content {
+  Js(Box<JsModule>),
+  /// A module that is inlined as a JavaScript expression.
+  Value(String),
+}
+
+struct JsModule {
+  text: Arc<str>,
+  /// The imports of the module, by the name they are bound to.
+  imports: HashMap<String, Import>,
+  exports: IndexMap<String, Export>,
+  star_exports: Vec<Target>,
+  /// The statically imported modules, in evaluation order.
+  dependencies: Vec<Target>,
+  dynamic_imports: Vec<(Range<usize>, Target)>,
+  /// The import and export statements, or the `export` keywords of
+  /// declarations, that are removed.
+  removals: Vec<Range<usize>>,
+  default_export: Option<DefaultExport>,
+  /// The references to the top level bindings of the module.
+  references: Vec<Reference>,
+  /// The top level bindings declared by the module, in order of appearance.
+  top_level: IndexSet<String>,
+}
+
+#[derive(Clone)]
+enum Target {
+  Module(usize),
+  /// A module that is not in the archive, by its resolved specifier.
+  External(String),
+}
+
+#[derive(Clone)]
+struct Import {
+  target: Target,
+  /// The imported name, or `None` for the namespace.
+  name: Option<String>,
+}
+
+#[derive(Clone)]
+enum Export {
+  Local(String),
+  Import(Import),
+  /// An `export default` expression or anonymous declaration.
+  Default,
+}
+
+/// The `export default` of an expression, which is declared as a constant.
+struct DefaultExport {
+  prefix: Range<usize>,
+  /// Where a semicolon has to be inserted to end the declaration.
+  semicolon: Option<usize>,
+}
+
+struct Reference {
+  range: Range<usize>,
+  name: String,
+  /// Whether the reference is a shorthand property, whose key has to be
+  /// kept when it is renamed.
+  shorthand: bool,
+}
+
+/// The bindings imported from a module that is not in the archive.
+#[derive(Default)]
+struct External {
+  namespace: Option<String>,
+  named: IndexMap<String, String>,
+}
+
+impl Bundler<'_> {
+  async fn load_next(&mut self) -> Result<(), BundleError> {
+    let specifier = self.specifiers[self.modules.len()].clone();
+    let module = self
+      .eszip
+      .get_module(&specifier)
+      .ok_or_else(|| BundleError::NotFound(specifier.clone()))?;
+    let source = module
+      .source()
+      .await
+      .ok_or_else(|| BundleError::SourceUnavailable(specifier.clone()))?;
+    let invalid = |err: &dyn std::fmt::Display| {
+      BundleError::Parse(specifier.clone(), err.to_string())
+    };
+    let content = match module.kind {
+      ModuleKind::JavaScript => {
+        let text = std::str::from_utf8(&source).map_err(|err| invalid(&err))?;
+        let parsed = deno_ast::parse_module(ParseParams {
+          specifier: specifier_url(&specifier).map_err(|err| invalid(&err))?,
+          text: text.into(),
+          media_type: MediaType::JavaScript,
+          capture_tokens: false,
+          scope_analysis: true,
+          maybe_syntax: None,
+        })
+        .map_err(|err| invalid(&err))?;
+        Content::Js(Box::new(self.analyze(&specifier, &parsed)?))
+      }
+      ModuleKind::Json => {
+        let value = serde_json::from_slice::<serde_json::Value>(&source)
+          .map_err(|err| invalid(&err))?;
+        Content::Value(value.to_string())
+      }
+      ModuleKind::Text => {
+        let text = std::str::from_utf8(&source).map_err(|err| invalid(&err))?;
+        Content::Value(serde_json::to_string(text).unwrap())
+      }
+      ModuleKind::Bytes => Content::Value(format!(
+        "new Uint8Array([{}])",
+        source
+          .iter()
+          .map(|byte| byte.to_string())
+          .collect::<Vec<_>>()
+          .join(",")
+      )),
+      kind => return Err(BundleError::Unsupported(specifier, kind)),
+    };
+    self.modules.push(BundledModule {
+      specifier,
+      content,
+      renames: HashMap::new(),
+      default_name: None,
+    });
+    Ok(())
+  }
+
+  /// Resolve an import of `referrer`, registering the imported module to be
+  /// loaded if it is in the archive.
+  fn resolve(
+    &mut self,
+    specifier: &str,
+    referrer: &str,
+  ) -> Result<Target, BundleError> {
+    let unresolved = || BundleError::Unresolved {
+      specifier: specifier.to_string(),
+      referrer: referrer.to_string(),
+    };
+    let referrer_url = specifier_url(referrer).map_err(|_| unresolved())?;
+    let resolved = match &self.import_map {
+      Some(import_map) => import_map.resolve(specifier, &referrer_url).ok(),
+      None => deno_graph::resolve_import(specifier, &referrer_url).ok(),
+    }
+    .ok_or_else(unresolved)?;
+    let module = self.eszip.get_module(resolved.as_str()).or_else(|| {
+      let relative = resolved.as_str().strip_prefix(RELATIVE_BASE)?;
+      self.eszip.get_module(relative)
+    });
+    match module {
+      Some(module) => {
+        let (index, _) = self.specifiers.insert_full(module.specifier);
+        Ok(Target::Module(index))
+      }
+      None if resolved.scheme() == "file" => {
+        Err(BundleError::NotFound(resolved.to_string()))
+      }
+      None => Ok(Target::External(resolved.to_string())),
+    }
+  }
+
+  fn analyze(
+    &mut self,
+    specifier: &str,
+    parsed: &deno_ast::ParsedSource,
+  ) -> Result<JsModule, BundleError> {
+    let start = parsed.range().start.as_byte_pos().0;
+    let range =
+      |span: Span| (span.lo.0 - start) as usize..(span.hi.0 - start) as usize;
+    let text = parsed.text().clone();
+    let program = parsed.program_ref();
+    let module = program.unwrap_module();
+    let mut js = JsModule {
+      text: text.clone(),
+      imports: HashMap::new(),
+      exports: IndexMap::new(),
+      star_exports: Vec::new(),
+      dependencies: Vec::new(),
+      dynamic_imports: Vec::new(),
+      removals: Vec::new(),
+      default_export: None,
+      references: Vec::new(),
+      top_level: IndexSet::new(),
+    };
+
+    // the imports are bound before the module is evaluated, so they are
+    // collected first
+    for item in &module.body {
+      let ModuleItem::ModuleDecl(decl) = item else {
+        continue;
+      };
+      match decl {
+        ModuleDecl::Import(import) => {
+          if import.type_only {
+            continue;
+          }
+          let target = self.resolve(&import.src.value, specifier)?;
+          js.dependencies.push(target.clone());
+          js.removals.push(range(import.span));
+          for specifier in &import.specifiers {
+            let (local, name) = match specifier {
+              ImportSpecifier::Named(specifier) => (
+                &specifier.local,
+                Some(specifier.imported.as_ref().map_or_else(
+                  || specifier.local.sym.to_string(),
+                  export_name,
+                )),
+              ),
+              ImportSpecifier::Default(specifier) => {
+                (&specifier.local, Some("default".to_string()))
+              }
+              ImportSpecifier::Namespace(specifier) => (&specifier.local, None),
+            };
+            js.imports.insert(
+              local.sym.to_string(),
+              Import {
+                target: target.clone(),
+                name,
+              },
+            );
+          }
+        }
+        ModuleDecl::ExportAll(export) => {
+          let target = self.resolve(&export.src.value, specifier)?;
+          js.dependencies.push(target.clone());
+          js.star_exports.push(target);
+          js.removals.push(range(export.span));
+        }
+        ModuleDecl::ExportNamed(NamedExport {
+          span,
+          specifiers,
+          src: Some(src),
+          ..
+        }) => {
+          let target = self.resolve(&src.value, specifier)?;
+          js.dependencies.push(target.clone());
+          js.removals.push(range(*span));
+          for specifier in specifiers {
+            let (exported, name) = match specifier {
+              ExportSpecifier::Named(specifier) => (
+                export_name(
+                  specifier.exported.as_ref().unwrap_or(&specifier.orig),
+                ),
+                Some(export_name(&specifier.orig)),
+              ),
+              ExportSpecifier::Namespace(specifier) => {
+                (export_name(&specifier.name), None)
+              }
+              ExportSpecifier::Default(specifier) => {
+                (specifier.exported.sym.to_string(), Some("default".into()))
+              }
+            };
+            js.exports.insert(
+              exported,
+              Export::Import(Import {
+                target: target.clone(),
+                name,
+              }),
+            );
+          }
+        }
+        _ => {}
+      }
+    }
+
+    let mut collector = Collector {
+      top_level: parsed.top_level_context(),
+      start,
+      references: Vec::new(),
+      dynamic_imports: Vec::new(),
+      idents: HashSet::new(),
+    };
+    for item in &module.body {
+      let decl = match item {
+        ModuleItem::Stmt(stmt) => {
+          stmt.visit_with(&mut collector);
+          continue;
+        }
+        ModuleItem::ModuleDecl(decl) => decl,
+      };
+      match decl {
+        ModuleDecl::ExportDecl(export) => {
+          js.removals
+            .push(range(export.span.with_hi(export.decl.span().lo)));
+          let names = match &export.decl {
+            Decl::Class(class) => vec![class.ident.sym.to_string()],
+            Decl::Fn(function) => vec![function.ident.sym.to_string()],
+            Decl::Var(var) => var
+              .decls
+              .iter()
+              .flat_map(|decl| find_pat_ids::<_, Id>(&decl.name))
+              .map(|(sym, _)| sym.to_string())
+              .collect(),
+            _ => Vec::new(),
+          };
+          for name in names {
+            js.exports.insert(name.clone(), Export::Local(name));
+          }
+          export.decl.visit_with(&mut collector);
+        }
+        ModuleDecl::ExportNamed(NamedExport {
+          span,
+          specifiers,
+          src: None,
+          ..
+        }) => {
+          js.removals.push(range(*span));
+          for specifier in specifiers {
+            let ExportSpecifier::Named(specifier) = specifier else {
+              continue;
+            };
+            let local = export_name(&specifier.orig);
+            let exported = specifier
+              .exported
+              .as_ref()
+              .map_or_else(|| local.clone(), export_name);
+            let export = match js.imports.get(&local) {
+              Some(import) => Export::Import(import.clone()),
+              None => Export::Local(local),
+            };
+            js.exports.insert(exported, export);
+          }
+        }
+        ModuleDecl::ExportDefaultDecl(export) => {
+          let (ident, decl_span) = match &export.decl {
+            DefaultDecl::Class(class) => (&class.ident, class.class.span),
+            DefaultDecl::Fn(function) => {
+              (&function.ident, function.function.span)
+            }
+            DefaultDecl::TsInterfaceDecl(_) => continue,
+          };
+          let prefix = range(export.span.with_hi(decl_span.lo));
+          match ident {
+            Some(ident) => {
+              js.removals.push(prefix);
+              js.exports.insert(
+                "default".to_string(),
+                Export::Local(ident.sym.to_string()),
+              );
+            }
+            None => {
+              js.default_export = Some(DefaultExport {
+                prefix,
+                semicolon: Some(range(export.span).end),
+              });
+              js.exports.insert("default".to_string(), Export::Default);
+            }
+          }
+          export.decl.visit_with(&mut collector);
+        }
+        ModuleDecl::ExportDefaultExpr(export) => {
+          let statement = range(export.span);
+          js.default_export = Some(DefaultExport {
+            prefix: range(export.span.with_hi(export.expr.span().lo)),
+            semicolon: (!text[statement.clone()].ends_with(';'))
+              .then_some(statement.end),
+          });
+          js.exports.insert("default".to_string(), Export::Default);
+          export.expr.visit_with(&mut collector);
+        }
+        _ => {}
+      }
+    }
+
+    self.idents.extend(collector.idents);
+    for (range, dynamic_specifier) in collector.dynamic_imports {
+      let target = self.resolve(&dynamic_specifier, specifier)?;
+      js.dynamic_imports.push((range, target));
+    }
+    js.top_level = collector
+      .references
+      .iter()
+      .filter(|reference| !js.imports.contains_key(&reference.name))
+      .map(|reference| reference.name.clone())
+      .collect();
+    js.references = collector.references;
+    Ok(js)
+  }
+
+  fn emit(mut self) -> Result<String, BundleError> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    for index in 0..self.modules.len() {
+      self.evaluation_order(index, &mut visited, &mut order);
+    }
+
+    for &index in &order {
+      let module = &self.modules[index];
+      let stem = specifier_stem(&module.specifier);
+      let top_level = match &module.content {
+        Content::Js(js) => js.top_level.clone(),
+        Content::Value(_) => IndexSet::new(),
+      };
+      let has_default = match &module.content {
+        Content::Js(js) => js.default_export.is_some(),
+        Content::Value(_) => true,
+      };
+      let mut renames = HashMap::new();
+      for name in top_level {
+        let renamed =
+          if !self.idents.contains(&name) && self.names.insert(name.clone()) {
+            name.clone()
+          } else {
+            self.fresh_name(&name)
+          };
+        renames.insert(name, renamed);
+      }
+      let default_name =
+        has_default.then(|| self.fresh_name(&format!("{stem}_default")));
+      let module = &mut self.modules[index];
+      module.renames = renames;
+      module.default_name = default_name;
+    }
+
+    let mut body = String::new();
+    for &index in &order {
+      let module = &self.modules[index];
+      body.push_str(&format!("// {}\n", module.specifier));
+      match &module.content {
+        Content::Js(_) => {
+          body.push_str(&self.transform(index)?);
+        }
+        Content::Value(value) => {
+          body.push_str(&format!(
+            "const {} = {value};",
+            module.default_name.as_ref().unwrap()
+          ));
+        }
+      }
+      if !body.ends_with('\n') {
+        body.push('\n');
+      }
+    }
+
+    let mut exports = Vec::new();
+    let mut external_stars = Vec::new();
+    for name in self.export_names(0, &mut HashSet::new(), &mut external_stars) {
+      let binding = self.export_binding(0, &name)?;
+      exports.push(if binding == name {
+        binding
+      } else {
+        format!("{binding} as {}", quote_export_name(&name))
+      });
+    }
+    if !exports.is_empty() {
+      body.push_str(&format!("export {{ {} }};\n", exports.join(", ")));
+    }
+    for specifier in external_stars {
+      body.push_str(&format!("export * from {};\n", quote(&specifier)));
+    }
+
+    // the getters of namespace objects can refer to other namespaces
+    let mut namespaces = Vec::new();
+    while namespaces.len() < self.namespaces.len() {
+      let (&index, name) = self.namespaces.get_index(namespaces.len()).unwrap();
+      let name = name.clone();
+      let mut getters = Vec::new();
+      for export in
+        self.export_names(index, &mut HashSet::new(), &mut Vec::new())
+      {
+        let binding = self.export_binding(index, &export)?;
+        getters.push(format!(
+          "  get {}() {{ return {binding}; }},\n",
+          quote_export_name(&export)
+        ));
+      }
+      namespaces.push(format!(
+        "const {name} = Object.freeze({{\n  __proto__: null,\n  \
+         [Symbol.toStringTag]: \"Module\",\n{}}});\n",
+        getters.concat()
+      ));
+    }
+
+    let mut bundle = String::new();
+    for (specifier, external) in &self.externals {
+      let specifier = quote(specifier);
+      if let Some(namespace) = &external.namespace {
+        bundle
+          .push_str(&format!("import * as {namespace} from {specifier};\n"));
+      }
+      if !external.named.is_empty() {
+        let named = external
+          .named
+          .iter()
+          .map(|(name, local)| {
+            format!("{} as {local}", quote_export_name(name))
+          })
+          .collect::<Vec<_>>();
+        bundle.push_str(&format!(
+          "import {{ {} }} from {specifier};\n",
+          named.join(", ")
+        ));
+      }
+      if external.namespace.is_none() && external.named.is_empty() {
+        bundle.push_str(&format!("import {specifier};\n"));
+      }
+    }
+    bundle.push_str(&namespaces.concat());
+    bundle.push_str(&body);
+    Ok(bundle)
+  }
+
+  /// Append the modules that `index` imports, and then `index`, to `order`,
+  /// like they are evaluated.
+  fn evaluation_order(
+    &mut self,
+    index: usize,
+    visited: &mut HashSet<usize>,
+    order: &mut Vec<usize>,
+  ) {
+    if !visited.insert(index) {
+      return;
+    }
+    let dependencies = match &self.modules[index].content {
+      Content::Js(js) => js.dependencies.clone(),
+      Content::Value(_) => Vec::new(),
+    };
+    for dependency in dependencies {
+      match dependency {
+        Target::Module(dependency) => {
+          self.evaluation_order(dependency, visited, order)
+        }
+        Target::External(specifier) => {
+          self.externals.entry(specifier).or_default();
+        }
+      }
+    }
+    order.push(index);
+  }
+
+  /// Apply the renames and replace the imports of the JavaScript module
+  /// `index`.
+  fn transform(&mut self, index: usize) -> Result<String, BundleError> {
+    let Content::Js(js) = &self.modules[index].content else {
+      unreachable!();
+    };
+    let mut changes = js
+      .removals
+      .iter()
+      .map(|range| {
+        // statements are removed with their line break
+        let line_break = ["\r\n", "\n"]
+          .into_iter()
+          .find(|line_break| js.text[range.end..].starts_with(line_break))
+          .map_or(0, str::len);
+        TextChange {
+          range: range.start..range.end + line_break,
+          new_text: String::new(),
+        }
+      })
+      .collect::<Vec<_>>();
+    if let Some(default_export) = &js.default_export {
+      let name = self.modules[index].default_name.as_ref().unwrap();
+      changes.push(TextChange {
+        range: default_export.prefix.clone(),
+        new_text: format!("const {name} = "),
+      });
+      if let Some(semicolon) = default_export.semicolon {
+        changes.push(TextChange {
+          range: semicolon..semicolon,
+          new_text: ";".to_string(),
+        });
+      }
+    }
+
+    let text = js.text.clone();
+    let references = js
+      .references
+      .iter()
+      .map(|reference| {
+        (
+          reference.range.clone(),
+          reference.name.clone(),
+          reference.shorthand,
+          js.imports.get(&reference.name).cloned(),
+        )
+      })
+      .collect::<Vec<_>>();
+    let dynamic_imports = js.dynamic_imports.clone();
+    for (range, name, shorthand, import) in references {
+      let binding = match import {
+        Some(import) => self.import_binding(&import)?,
+        None => self.modules[index].renames[&name].clone(),
+      };
+      if binding == name {
+        continue;
+      }
+      changes.push(TextChange {
+        range,
+        new_text: if shorthand {
+          format!("{name}: {binding}")
+        } else {
+          binding
+        },
+      });
+    }
+    for (range, target) in dynamic_imports {
+      changes.push(TextChange {
+        range,
+        new_text: match target {
+          Target::Module(target) => {
+            format!("Promise.resolve({})", self.namespace(target))
+          }
+          Target::External(specifier) => {
+            format!("import({})", quote(&specifier))
+          }
+        },
+      });
+    }
+    Ok(deno_ast::apply_text_changes(&text, changes))
+  }
+
+  /// The name of the binding that `import` refers to in the bundle.
+  fn import_binding(&mut self, import: &Import) -> Result<String, BundleError> {
+    match (&import.target, &import.name) {
+      (Target::Module(index), Some(name)) => self.export_binding(*index, name),
+      (Target::Module(index), None) => Ok(self.namespace(*index)),
+      (Target::External(specifier), name) => {
+        Ok(self.external(specifier, name.as_deref()))
+      }
+    }
+  }
+
+  fn export_binding(
+    &mut self,
+    index: usize,
+    name: &str,
+  ) -> Result<String, BundleError> {
+    self
+      .find_export(index, name, &mut HashSet::new())?
+      .ok_or_else(|| BundleError::MissingExport {
+        specifier: self.modules[index].specifier.clone(),
+        name: name.to_string(),
+      })
+  }
+
+  /// Find the binding of the export `name` of the module `index`, following
+  /// re-exports.
+  fn find_export(
+    &mut self,
+    index: usize,
+    name: &str,
+    visited: &mut HashSet<(usize, String)>,
+  ) -> Result<Option<String>, BundleError> {
+    if !visited.insert((index, name.to_string())) {
+      return Ok(None);
+    }
+    let module = &self.modules[index];
+    let js = match &module.content {
+      Content::Js(js) => js,
+      Content::Value(_) => {
+        return Ok(
+          (name == "default").then(|| module.default_name.clone().unwrap()),
+        );
+      }
+    };
+    if let Some(export) = js.exports.get(name) {
+      return match export.clone() {
+        Export::Local(local) => Ok(module.renames.get(&local).cloned()),
+        Export::Default => Ok(module.default_name.clone()),
+        Export::Import(Import {
+          target: Target::Module(target),
+          name: Some(name),
+        }) => self.find_export(target, &name, visited),
+        Export::Import(import) => self.import_binding(&import).map(Some),
+      };
+    }
+    if name == "default" {
+      return Ok(None);
+    }
+    let star_exports = js.star_exports.clone();
+    let mut external = None;
+    for target in star_exports {
+      match target {
+        Target::Module(target) => {
+          if let Some(binding) = self.find_export(target, name, visited)? {
+            return Ok(Some(binding));
+          }
+        }
+        Target::External(specifier) => {
+          external.get_or_insert(specifier);
+        }
+      }
+    }
+    Ok(external.map(|specifier| self.external(&specifier, Some(name))))
+  }
+
+  /// Collect the names that the module `index` exports, and the modules that
+  /// are not in the archive that it re-exports all names of.
+  fn export_names(
+    &self,
+    index: usize,
+    visited: &mut HashSet<usize>,
+    external_stars: &mut Vec<String>,
+  ) -> IndexSet<String> {
+    let mut names = IndexSet::new();
+    if !visited.insert(index) {
+      return names;
+    }
+    let js = match &self.modules[index].content {
+      Content::Js(js) => js,
+      Content::Value(_) => {
+        names.insert("default".to_string());
+        return names;
+      }
+    };
+    names.extend(js.exports.keys().cloned());
+    for target in &js.star_exports {
+      match target {
+        Target::Module(target) => names.extend(
+          self
+            .export_names(*target, visited, external_stars)
+            .into_iter()
+            .filter(|name| name != "default"),
+        ),
+        Target::External(specifier) => {
+          if !external_stars.contains(specifier) {
+            external_stars.push(specifier.clone());
+          }
+        }
+      }
+    }
+    names
+  }
+
+  /// The name of the namespace object of the module `index`.
+  fn namespace(&mut self, index: usize) -> String {
+    if let Some(name) = self.namespaces.get(&index) {
+      return name.clone();
+    }
+    let stem = specifier_stem(&self.modules[index].specifier);
+    let name = self.fresh_name(&format!("{stem}_ns"));
+    self.namespaces.insert(index, name.clone());
+    name
+  }
+
+  /// The name that the export `name`, or the namespace, of the module that
+  /// is not in the archive is imported as.
+  fn external(&mut self, specifier: &str, name: Option<&str>) -> String {
+    let stem = specifier_stem(specifier);
+    let existing =
+      self
+        .externals
+        .get(specifier)
+        .and_then(|external| match name {
+          Some(name) => external.named.get(name),
+          None => external.namespace.as_ref(),
+        });
+    if let Some(local) = existing {
+      return local.clone();
+    }
+    let local = match name {
+      Some(name) => self.fresh_name(&specifier_stem(&format!("{stem}_{name}"))),
+      None => self.fresh_name(&format!("{stem}_ns")),
+    };
+    let external = self.externals.entry(specifier.to_string()).or_default();
+    match name {
+      Some(name) => {
+        external.named.insert(name.to_string(), local.clone());
+      }
+      None => external.namespace = Some(local.clone()),
+    }
+    local
+  }
+
+  /// Take a name that is not taken and does not appear in any module,
+  /// starting with `base`.
+  fn fresh_name(&mut self, base: &str) -> String {
+    let mut name = base.to_string();
+    let mut suffix = 0;
+    while self.idents.contains(&name) || !self.names.insert(name.clone()) {
+      suffix += 1;
+      name = format!("{base}${suffix}");
+    }
+    name
+  }
+}
+
+/// Collects the references to the top level bindings of a module, and its
+/// dynamic imports of string literals.
+struct Collector {
+  top_level: SyntaxContext,
+  start: u32,
+  references: Vec<Reference>,
+  dynamic_imports: Vec<(Range<usize>, String)>,
+  /// The identifiers that appear in the module other than as references to
+  /// its top level bindings.
+  idents: HashSet<String>,
+}
+
+impl Collector {
+  fn range(&self, span: Span) -> Range<usize> {
+    (span.lo.0 - self.start) as usize..(span.hi.0 - self.start) as usize
+  }
+
+  /// Record `ident`, returning whether it refers to a top level binding.
+  fn reference(&mut self, ident: &Ident, shorthand: bool) -> bool {
+    if ident.ctxt != self.top_level {
+      self.idents.insert(ident.sym.to_string());
+      return false;
+    }
+    self.references.push(Reference {
+      range: self.range(ident.span),
+      name: ident.sym.to_string(),
+      shorthand,
+    });
+    true
+  }
+}
+
+impl Visit for Collector {
+  fn visit_ident(&mut self, ident: &Ident) {
+    self.reference(ident, false);
+  }
+
+  fn visit_prop(&mut self, prop: &Prop) {
+    match prop {
+      Prop::Shorthand(ident) => {
+        self.reference(ident, true);
+      }
+      _ => prop.visit_children_with(self),
+    }
+  }
+
+  fn visit_assign_pat_prop(&mut self, prop: &AssignPatProp) {
+    self.reference(&prop.key.id, true);
+    prop.value.visit_with(self);
+  }
+
+  fn visit_call_expr(&mut self, call: &CallExpr) {
+    if let (Callee::Import(_), Some(argument)) =
+      (&call.callee, call.args.first())
+    {
+      if let (None, Expr::Lit(Lit::Str(specifier))) =
+        (&argument.spread, &*argument.expr)
+      {
+        self
+          .dynamic_imports
+          .push((self.range(call.span), specifier.value.to_string()));
+        return;
+      }
+    }
+    call.visit_children_with(self);
+  }
+}
+
+/// Parse a specifier of the archive as a URL, resolving relative specifiers
+/// against [`RELATIVE_BASE`].
+fn specifier_url(specifier: &str) -> Result<ModuleSpecifier, url::ParseError> {
+  ModuleSpecifier::parse(specifier)
+    .or_else(|_| ModuleSpecifier::parse(RELATIVE_BASE)?.join(specifier))
+}
+
+fn export_name(name: &ModuleExportName) -> String {
+  match name {
+    ModuleExportName::Ident(ident) => ident.sym.to_string(),
+    ModuleExportName::Str(str) => str.value.to_string(),
+  }
+}
+
+/// An identifier derived from the last segment of `specifier`, to name the
+/// bindings the bundle declares for the module.
+fn specifier_stem(specifier: &str) -> String {
+  let segment = specifier
+    .split(['?', '#'])
+    .next()
+    .unwrap_or_default()
+    .trim_end_matches('/')
+    .rsplit(['/', ':'])
+    .next()
+    .unwrap_or_default();
+  let segment = segment.split('.').next().unwrap_or_default();
+  let mut stem = segment
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || c == '_' || c == '$' {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect::<String>();
+  if !stem.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+    stem.insert(0, '_');
+  }
+  stem
+}
+
+fn quote(text: &str) -> String {
+  serde_json::to_string(text).unwrap()
+}
+
+/// Quote an export name that is not an identifier name.
+fn quote_export_name(name: &str) -> String {
+  let is_identifier_name = name
+    .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+  if is_identifier_name {
+    name.to_string()
+  } else {
+    quote(name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn add_modules(eszip: &mut EszipV2, modules: &[(&str, ModuleKind, &str)]) {
+    for (specifier, kind, source) in modules {
+      eszip.add_module(
+        *kind,
+        specifier.to_string(),
+        source.as_bytes().to_vec(),
+        Vec::new(),
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn to_single_module() {
+    let mut eszip = EszipV2::default();
+    add_modules(
+      &mut eszip,
+      &[
+        (
+          "file:///main.js",
+          ModuleKind::JavaScript,
+          "import { a, b as renamed } from './a.js';
+import * as ns from './b.js';
+import data from './data.json' with { type: 'json' };
+import chalk from 'npm:chalk@5';
+const local = 'main';
+export const sum = a + renamed.length + ns.local + data.value;
+export function greet() {
+  return chalk.green({ local, a });
+}
+export default () => import('./lazy.js');
+export * from './b.js';
+",
+        ),
+        (
+          "file:///a.js",
+          ModuleKind::JavaScript,
+          "const local = 'a';\nexport const a = 1;\nexport { local as b };\n",
+        ),
+        (
+          "file:///b.js",
+          ModuleKind::JavaScript,
+          "export let local = 2;\n",
+        ),
+        (
+          "file:///lazy.js",
+          ModuleKind::JavaScript,
+          "export const lazy = true;\n",
+        ),
+        ("file:///data.json", ModuleKind::Json, "{ \"value\": 3 }"),
+      ],
+    );
+
+    let bundle = super::to_single_module(&eszip, "file:///main.js")
+      .await
+      .unwrap();
+    assert_eq!(
+      bundle,
+      r#"import { default as chalk_5_default } from "npm:chalk@5";
+const b_ns = Object.freeze({
+  __proto__: null,
+  [Symbol.toStringTag]: "Module",
+  get local() { return local$1; },
+});
+const lazy_ns = Object.freeze({
+  __proto__: null,
+  [Symbol.toStringTag]: "Module",
+  get lazy() { return lazy; },
+});
+// file:///a.js
+const local = 'a';
+const a = 1;
+// file:///b.js
+let local$1 = 2;
+// file:///data.json
+const data_default = {"value":3};
+// file:///main.js
+const local$2 = 'main';
+const sum = a + local.length + b_ns.local + data_default.value;
+function greet() {
+  return chalk_5_default.green({ local: local$2, a });
+}
+const main_default = () => Promise.resolve(lazy_ns);
+// file:///lazy.js
+const lazy = true;
+export { sum, greet, main_default as default, local$1 as local };
+"#
+    );
+  }
+
+  #[tokio::test]
+  async fn to_single_module_errors() {
+    let mut eszip = EszipV2::default();
+    add_modules(
+      &mut eszip,
+      &[
+        (
+          "file:///main.js",
+          ModuleKind::JavaScript,
+          "import { missing } from './a.js';\nconsole.log(missing);\n",
+        ),
+        (
+          "file:///a.js",
+          ModuleKind::JavaScript,
+          "export const a = 1;\n",
+        ),
+        ("file:///b.js", ModuleKind::JavaScript, "import './c.js';\n"),
+      ],
+    );
+
+    let err = super::to_single_module(&eszip, "file:///main.js")
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "module 'file:///a.js' does not export 'missing'"
+    );
+    let err = super::to_single_module(&eszip, "file:///b.js")
+      .await
+      .unwrap_err();
+    assert!(matches!(err, BundleError::NotFound(specifier)
+      if specifier == "file:///c.js"));
+    let err = super::to_single_module(&eszip, "file:///d.js")
+      .await
+      .unwrap_err();
+    assert!(matches!(err, BundleError::NotFound(_)));
+  }
+}
@@ -0,0 +1,167 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! A minimal C API for reading eszips from runtimes that are not written in
+//! Rust, enabled with the `capi` feature. Build it as a shared library with:
+//!
+//! ```sh
+//! cargo rustc --release --features capi --crate-type cdylib
+//! ```
+//!
+//! The functions are:
+//!
+//! ```c
+//! typedef struct EszipHandle EszipHandle;
+//!
+//! EszipHandle* eszip_parse(const uint8_t* data, size_t len);
+//! const uint8_t* eszip_get_module(const EszipHandle* eszip,
+//!                                 const char* specifier, size_t* len);
+//! void eszip_free(EszipHandle* eszip);
+//! ```
+//!
+//! Panics are caught at the boundary, as unwinding into C is undefined
+//! behavior; the functions return null instead.
+
+use std::collections::HashMap;
+use std::ffi::c_char;
+use std::ffi::CStr;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::Eszip;
+
+/// An eszip parsed by [`eszip_parse`], together with the sources handed out
+/// by [`eszip_get_module`], which are kept alive until [`eszip_free`].
+pub struct EszipHandle {
+  eszip: Eszip,
+  sources: Mutex<HashMap<String, Arc<[u8]>>>,
+}
+
+/// Parse an eszip of any version from `len` bytes at `data`, including the
+/// sources of all its modules.
+///
+/// Returns null if the eszip is invalid. The returned eszip must be released
+/// with [`eszip_free`].
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn eszip_parse(
+  data: *const u8,
+  len: usize,
+) -> *mut EszipHandle {
+  if data.is_null() {
+    return std::ptr::null_mut();
+  }
+  let bytes = std::slice::from_raw_parts(data, len).to_vec();
+  catch_unwind(|| {
+    let result = futures::executor::block_on(async move {
+      let (eszip, loader) =
+        Eszip::parse(futures::io::Cursor::new(bytes)).await?;
+      loader.await?;
+      Ok::<_, crate::ParseError>(eszip)
+    });
+    match result {
+      Ok(eszip) => Box::into_raw(Box::new(EszipHandle {
+        eszip,
+        sources: Default::default(),
+      })),
+      Err(_) => std::ptr::null_mut(),
+    }
+  })
+  .unwrap_or(std::ptr::null_mut())
+}
+
+/// Get the source of the module with the given specifier, following
+/// redirects, and write its length to `len`.
+///
+/// Returns null if there is no such module. The source stays valid until the
+/// eszip is released with [`eszip_free`].
+///
+/// # Safety
+///
+/// `eszip` must have been returned by [`eszip_parse`] and not freed yet,
+/// `specifier` must be a nul-terminated string and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn eszip_get_module(
+  eszip: *const EszipHandle,
+  specifier: *const c_char,
+  len: *mut usize,
+) -> *const u8 {
+  let (Some(handle), false) = (eszip.as_ref(), specifier.is_null()) else {
+    return std::ptr::null();
+  };
+  let Ok(specifier) = CStr::from_ptr(specifier).to_str() else {
+    return std::ptr::null();
+  };
+  catch_unwind(AssertUnwindSafe(|| {
+    let Some(module) = handle.eszip.get_module(specifier) else {
+      return std::ptr::null();
+    };
+    let Some(source) = futures::executor::block_on(module.source()) else {
+      return std::ptr::null();
+    };
+    let mut sources = handle.sources.lock().unwrap();
+    let source = sources.entry(specifier.to_string()).or_insert(source);
+    if !len.is_null() {
+      *len = source.len();
+    }
+    source.as_ptr()
+  }))
+  .unwrap_or(std::ptr::null())
+}
+
+/// Release an eszip returned by [`eszip_parse`], and the sources returned for
+/// it by [`eszip_get_module`].
+///
+/// # Safety
+///
+/// `eszip` must be null or have been returned by [`eszip_parse`], and must not
+/// be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn eszip_free(eszip: *mut EszipHandle) {
+  if !eszip.is_null() {
+    let handle = Box::from_raw(eszip);
+    // a panic while dropping leaks what is left of the eszip
+    let _ = catch_unwind(AssertUnwindSafe(|| drop(handle)));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::ffi::CString;
+
+  use super::*;
+  use crate::EszipV2;
+  use crate::ModuleKind;
+
+  #[test]
+  fn parse_and_get_module() {
+    let mut eszip = EszipV2::default();
+    eszip.add_to_front(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"console.log(1);",
+      [],
+    );
+    let bytes = eszip.into_bytes();
+
+    unsafe {
+      let eszip = eszip_parse(bytes.as_ptr(), bytes.len());
+      assert!(!eszip.is_null());
+
+      let specifier = CString::new("file:///main.js").unwrap();
+      let mut len = 0;
+      let source = eszip_get_module(eszip, specifier.as_ptr(), &mut len);
+      assert_eq!(std::slice::from_raw_parts(source, len), b"console.log(1);");
+
+      let missing = CString::new("file:///missing.js").unwrap();
+      assert!(eszip_get_module(eszip, missing.as_ptr(), &mut len).is_null());
+
+      eszip_free(eszip);
+      assert!(eszip_parse(b"invalid".as_ptr(), 7).is_null());
+    }
+  }
+}
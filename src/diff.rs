@@ -0,0 +1,467 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Comparing two eszip archives, e.g. to review what a deployment changes.
+//! See [`diff`].
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use deno_graph::source::LoaderChecksum;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::inspect::OptionsDump;
+use crate::EszipV2;
+use crate::ModuleFlags;
+use crate::ModuleKind;
+
+/// The differences between two archives, see [`diff`]. Specifiers are listed
+/// in the order in which they are stored in the archive they are in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EszipDiff {
+  /// Entries that are only in the second archive.
+  pub added: Vec<EntryDiff>,
+  /// Entries that are only in the first archive.
+  pub removed: Vec<EntryDiff>,
+  /// Entries that are in both archives, but differ.
+  pub changed: Vec<EntryDiff>,
+  /// Modules of both archives that were not compared, because their source
+  /// or source map is not loaded in one of them.
+  pub not_compared: Vec<String>,
+  pub npm: NpmDiff,
+  /// The options of the archives, if they differ.
+  pub options: Option<OptionsDiff>,
+}
+
+impl EszipDiff {
+  /// Whether the archives have the same entries, npm packages and options.
+  /// Entries that were not compared are not taken into account.
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty()
+      && self.removed.is_empty()
+      && self.changed.is_empty()
+      && self.npm.is_empty()
+      && self.options.is_none()
+  }
+}
+
+/// An entry of the archives, as it is in the first archive (`before`) and in
+/// the second one (`after`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryDiff {
+  pub specifier: String,
+  pub before: Option<EntryState>,
+  pub after: Option<EntryState>,
+}
+
+/// What an entry of an archive is made of, as compared by [`diff`]. Contents
+/// are compared by their SHA-256 hashes, in the hex format of `deno.lock`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "entryKind")]
+pub enum EntryState {
+  #[serde(rename_all = "camelCase")]
+  Module {
+    kind: ModuleKind,
+    /// `None` if the module has no source.
+    source_hash: Option<String>,
+    /// `None` if the module has no source map.
+    source_map_hash: Option<String>,
+    /// The bits of the [`crate::ModuleFlags`] of the module.
+    flags: u8,
+  },
+  /// A module whose source is not stored in the archive, see
+  /// [`crate::EszipV2::add_external_module`].
+  External {
+    kind: ModuleKind,
+    url: String,
+    /// The bits of the [`crate::ModuleFlags`] of the module.
+    flags: u8,
+  },
+  Redirect {
+    target: String,
+  },
+}
+
+/// The differences between the npm resolution snapshots of two archives. An
+/// archive without a snapshot is compared as one with an empty snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmDiff {
+  /// Package requirements of the `npm:` specifiers that are added, removed
+  /// or resolved to another package.
+  pub root_packages: Vec<NpmRootDiff>,
+  /// Ids of the packages that are only in the second snapshot.
+  pub added_packages: Vec<String>,
+  /// Ids of the packages that are only in the first snapshot.
+  pub removed_packages: Vec<String>,
+}
+
+impl NpmDiff {
+  pub fn is_empty(&self) -> bool {
+    self.root_packages.is_empty()
+      && self.added_packages.is_empty()
+      && self.removed_packages.is_empty()
+  }
+}
+
+/// A package requirement, e.g. `chalk@5`, and the id of the package it
+/// resolves to in each snapshot, e.g. `chalk@5.3.0`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmRootDiff {
+  pub req: String,
+  pub before: Option<String>,
+  pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OptionsDiff {
+  pub before: OptionsDump,
+  pub after: OptionsDump,
+}
+
+/// Compare the entries, npm packages and options of two archives, `a` being
+/// the older one.
+///
+/// The contents of the modules are compared as they are loaded, so the
+/// futures returned by [`EszipV2::parse`] should have resolved. Modules whose
+/// source or source map is not loaded in one of the archives are listed in
+/// [`EszipDiff::not_compared`] instead of being compared.
+pub fn diff(a: &EszipV2, b: &EszipV2) -> EszipDiff {
+  let mut result = EszipDiff::default();
+  let (before, not_loaded_before) = entry_states(a);
+  let (after, not_loaded_after) = entry_states(b);
+  let after_map = after.iter().cloned().collect::<HashMap<_, _>>();
+  let before_map = before.iter().cloned().collect::<HashMap<_, _>>();
+
+  for (specifier, state) in before {
+    let Some(after_state) = after_map.get(&specifier) else {
+      result.removed.push(EntryDiff {
+        specifier,
+        before: Some(state),
+        after: None,
+      });
+      continue;
+    };
+    if not_loaded_before.contains(&specifier)
+      || not_loaded_after.contains(&specifier)
+    {
+      result.not_compared.push(specifier);
+    } else if state != *after_state {
+      result.changed.push(EntryDiff {
+        specifier,
+        before: Some(state),
+        after: Some(after_state.clone()),
+      });
+    }
+  }
+  for (specifier, state) in after {
+    if !before_map.contains_key(&specifier) {
+      result.added.push(EntryDiff {
+        specifier,
+        before: None,
+        after: Some(state),
+      });
+    }
+  }
+
+  result.npm = npm_diff(a, b);
+  let options = (OptionsDump::new(a.options()), OptionsDump::new(b.options()));
+  if options.0 != options.1 {
+    result.options = Some(OptionsDiff {
+      before: options.0,
+      after: options.1,
+    });
+  }
+  result
+}
+
+/// The state of each entry of `eszip`, in the order in which they are
+/// stored, and the modules whose content is not loaded.
+fn entry_states(
+  eszip: &EszipV2,
+) -> (Vec<(String, EntryState)>, BTreeSet<String>) {
+  let redirects = eszip.redirects();
+  let mut not_loaded = BTreeSet::new();
+  let mut states = eszip
+    .iter()
+    .filter(|(specifier, _)| !redirects.contains_key(specifier))
+    .map(|(specifier, module)| {
+      let flags = module.flags();
+      let state = if let Some(url) = module
+        .external_url()
+        .filter(|_| flags.contains(ModuleFlags::EXTERNAL))
+      {
+        EntryState::External {
+          kind: module.kind,
+          url: url.into(),
+          flags: flags.bits(),
+        }
+      } else {
+        let content = eszip.get_loaded_module_content(&specifier);
+        if content.is_none() {
+          not_loaded.insert(specifier.clone());
+        }
+        let (source, source_map) = content.unwrap_or_default();
+        EntryState::Module {
+          kind: module.kind,
+          source_hash: source.map(|source| LoaderChecksum::gen(&source)),
+          source_map_hash: (!source_map.is_empty())
+            .then(|| LoaderChecksum::gen(&source_map)),
+          flags: flags.bits(),
+        }
+      };
+      (specifier, state)
+    })
+    .collect::<HashMap<_, _>>();
+  for (specifier, target) in redirects {
+    states.insert(specifier, EntryState::Redirect { target });
+  }
+  let states = eszip
+    .specifiers()
+    .into_iter()
+    .filter_map(|specifier| {
+      let state = states.remove(&specifier)?;
+      Some((specifier, state))
+    })
+    .collect();
+  (states, not_loaded)
+}
+
+fn npm_diff(a: &EszipV2, b: &EszipV2) -> NpmDiff {
+  let snapshot = |eszip: &EszipV2| {
+    let mut roots = BTreeMap::new();
+    let mut packages = BTreeSet::new();
+    if let Some(snapshot) = eszip.npm_snapshot() {
+      let snapshot = snapshot.as_serialized();
+      for (req, id) in &snapshot.root_packages {
+        roots.insert(req.to_string(), id.as_serialized());
+      }
+      for package in &snapshot.packages {
+        packages.insert(package.id.as_serialized());
+      }
+    }
+    (roots, packages)
+  };
+  let (roots_before, packages_before) = snapshot(a);
+  let (roots_after, packages_after) = snapshot(b);
+
+  let reqs = roots_before
+    .keys()
+    .chain(roots_after.keys())
+    .collect::<BTreeSet<_>>();
+  NpmDiff {
+    root_packages: reqs
+      .into_iter()
+      .filter(|req| roots_before.get(*req) != roots_after.get(*req))
+      .map(|req| NpmRootDiff {
+        req: req.clone(),
+        before: roots_before.get(req).cloned(),
+        after: roots_after.get(req).cloned(),
+      })
+      .collect(),
+    added_packages: packages_after
+      .difference(&packages_before)
+      .cloned()
+      .collect(),
+    removed_packages: packages_before
+      .difference(&packages_after)
+      .cloned()
+      .collect(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use deno_npm::resolution::SerializedNpmResolutionSnapshot;
+  use deno_npm::resolution::SerializedNpmResolutionSnapshotPackage;
+  use deno_npm::NpmPackageId;
+  use deno_semver::package::PackageReq;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn npm_snapshot(
+    roots: &[(&str, &str)],
+  ) -> deno_npm::resolution::ValidSerializedNpmResolutionSnapshot {
+    let id = |id: &str| NpmPackageId::from_serialized(id).unwrap();
+    SerializedNpmResolutionSnapshot {
+      root_packages: roots
+        .iter()
+        .map(|(req, package)| (PackageReq::from_str(req).unwrap(), id(package)))
+        .collect(),
+      packages: roots
+        .iter()
+        .map(|(_, package)| SerializedNpmResolutionSnapshotPackage {
+          id: id(package),
+          system: Default::default(),
+          dist: Default::default(),
+          dependencies: Default::default(),
+          optional_dependencies: Default::default(),
+          bin: None,
+          scripts: Default::default(),
+          deprecated: Default::default(),
+        })
+        .collect(),
+    }
+    .into_valid()
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn diff_archives() {
+    let mut a = EszipV2::default();
+    a.add_module(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"import './a.js';",
+      [],
+    );
+    a.add_module(
+      ModuleKind::JavaScript,
+      "file:///a.js".to_string(),
+      *b"export {};",
+      *b"{}",
+    );
+    a.add_opaque_data("file:///removed".to_string(), Arc::new([1]));
+    a.add_redirect("file:///alias.js".to_string(), "file:///a.js".to_string());
+    a.add_npm_snapshot(npm_snapshot(&[
+      ("chalk@5", "chalk@5.3.0"),
+      ("ms@2", "ms@2.1.3"),
+    ]));
+
+    let mut b = EszipV2::default();
+    b.add_module(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"import './a.js';",
+      [],
+    );
+    b.add_module(
+      ModuleKind::JavaScript,
+      "file:///a.js".to_string(),
+      *b"export const a = 1;",
+      *b"{}",
+    );
+    b.add_opaque_data("file:///added".to_string(), Arc::new([2]));
+    b.add_redirect(
+      "file:///alias.js".to_string(),
+      "file:///main.js".to_string(),
+    );
+    b.add_npm_snapshot(npm_snapshot(&[("chalk@5", "chalk@5.4.0")]));
+
+    assert!(diff(&a, &a).is_empty());
+
+    let result = diff(&a, &b);
+    assert_eq!(
+      result.added,
+      vec![EntryDiff {
+        specifier: "file:///added".to_string(),
+        before: None,
+        after: Some(EntryState::Module {
+          kind: ModuleKind::OpaqueData,
+          source_hash: Some(LoaderChecksum::gen(&[2])),
+          source_map_hash: None,
+          flags: 0,
+        }),
+      }]
+    );
+    assert_eq!(
+      result
+        .removed
+        .iter()
+        .map(|entry| entry.specifier.as_str())
+        .collect::<Vec<_>>(),
+      vec!["file:///removed"]
+    );
+    assert_eq!(
+      result
+        .changed
+        .iter()
+        .map(|entry| entry.specifier.as_str())
+        .collect::<Vec<_>>(),
+      vec!["file:///a.js", "file:///alias.js"]
+    );
+    let Some(EntryState::Module {
+      source_hash,
+      source_map_hash,
+      ..
+    }) = &result.changed[0].after
+    else {
+      unreachable!()
+    };
+    assert_eq!(
+      source_hash.as_deref(),
+      Some(LoaderChecksum::gen(b"export const a = 1;").as_str())
+    );
+    assert_eq!(
+      source_map_hash.as_deref(),
+      Some(LoaderChecksum::gen(b"{}").as_str())
+    );
+    assert_eq!(
+      result.changed[1].after,
+      Some(EntryState::Redirect {
+        target: "file:///main.js".to_string()
+      })
+    );
+    assert!(result.not_compared.is_empty());
+    assert_eq!(
+      result.npm,
+      NpmDiff {
+        root_packages: vec![
+          NpmRootDiff {
+            req: "chalk@5".to_string(),
+            before: Some("chalk@5.3.0".to_string()),
+            after: Some("chalk@5.4.0".to_string()),
+          },
+          NpmRootDiff {
+            req: "ms@2".to_string(),
+            before: Some("ms@2.1.3".to_string()),
+            after: None,
+          },
+        ],
+        added_packages: vec!["chalk@5.4.0".to_string()],
+        removed_packages: vec![
+          "chalk@5.3.0".to_string(),
+          "ms@2.1.3".to_string()
+        ],
+      }
+    );
+    assert_eq!(result.options, None);
+  }
+
+  #[cfg(feature = "sha256")]
+  #[tokio::test]
+  async fn diff_options_and_unloaded_modules() {
+    use futures::io::BufReader;
+
+    use crate::v2::Checksum;
+
+    let mut a = EszipV2::default();
+    a.add_opaque_data("file:///data".to_string(), Arc::new([1; 8]));
+    let mut b = EszipV2::default();
+    b.add_opaque_data("file:///data".to_string(), Arc::new([1; 8]));
+    b.set_checksum(Checksum::Sha256);
+    let bytes = b.into_bytes();
+
+    let (b, fut) = EszipV2::parse(BufReader::new(bytes.as_slice()))
+      .await
+      .unwrap();
+    let result = diff(&a, &b);
+    assert_eq!(result.not_compared, vec!["file:///data".to_string()]);
+    let options = result.options.unwrap();
+    assert_eq!(options.before.checksum, Some(Checksum::NoChecksum));
+    assert_eq!(options.after.checksum, Some(Checksum::Sha256));
+
+    fut.await.unwrap();
+    let result = diff(&a, &b);
+    assert!(result.not_compared.is_empty());
+    assert!(result.changed.is_empty());
+  }
+}
@@ -12,6 +12,8 @@ pub enum ParseError {
   InvalidV2,
   #[error("invalid eszip v2 header hash")]
   InvalidV2HeaderHash,
+  #[error("invalid eszip v2 archive hash")]
+  InvalidV2ArchiveHash,
   #[error("invalid specifier in eszip v2 header at offset {0}")]
   InvalidV2Specifier(usize),
   #[error("invalid entry kind {0} in eszip v2 header at offset {0}")]
@@ -36,7 +38,121 @@ pub enum ParseError {
   InvalidV22OptionsHeader(String),
   #[error("invalid eszip v2.2 options header hash")]
   InvalidV22OptionsHeaderHash,
+  #[error("missing eszip v2 signature")]
+  MissingV2Signature,
+  #[error("invalid eszip v2 signature")]
+  InvalidV2Signature,
 
   #[error(transparent)]
   Io(#[from] std::io::Error),
 }
+
+/// An error returned by [`crate::EszipV2::from_graph`].
+#[derive(Debug, Error)]
+pub enum FromGraphError {
+  #[error("module not found {0}")]
+  ModuleNotFound(deno_graph::ModuleSpecifier),
+  #[error("failed to load '{specifier}': {source}")]
+  Load {
+    specifier: deno_graph::ModuleSpecifier,
+    source: Box<deno_graph::ModuleError>,
+  },
+  #[error("unsupported media type {media_type} for {specifier}")]
+  UnsupportedMediaType {
+    specifier: deno_graph::ModuleSpecifier,
+    media_type: deno_ast::MediaType,
+  },
+  #[error("failed to parse '{specifier}': {source}")]
+  Parse {
+    specifier: deno_graph::ModuleSpecifier,
+    source: Box<deno_ast::ParseDiagnostic>,
+  },
+  #[error("failed to emit '{specifier}': {source}")]
+  Emit {
+    specifier: deno_graph::ModuleSpecifier,
+    source: Box<deno_ast::TranspileError>,
+  },
+  #[error("failed to rewrite the sources of the source maps: {0:#}")]
+  RewriteSourceMapSources(anyhow::Error),
+}
+
+/// An error returned by [`crate::EszipV2::merge`] for entries that differ
+/// between the archives, with [`crate::v2::MergeConflict::Error`].
+#[derive(Debug, Error)]
+pub enum MergeError {
+  #[error("module '{0}' differs between the archives")]
+  Module(String),
+  #[error("npm req '{0}' resolves to different packages in the archives")]
+  NpmRoot(String),
+  #[error("npm workspace link '{0}' differs between the archives")]
+  NpmWorkspaceLink(String),
+  #[error("npm registry of scope '{0}' differs between the archives")]
+  NpmRegistry(String),
+  #[error("metadata '{0}' differs between the archives")]
+  Metadata(String),
+}
+
+/// An error returned by [`crate::Module::try_source`].
+#[derive(Debug, Error)]
+pub enum SourceError {
+  #[error("source of module '{0}' does not match its checksum")]
+  ChecksumMismatch(String),
+  #[error("source of module '{0}' could not be read from the archive")]
+  Unavailable(String),
+  /// The module was removed from the eszip, or renamed, after it was
+  /// returned.
+  #[error("module '{0}' is no longer in the eszip")]
+  NotFound(String),
+}
+
+/// An error returned by [`crate::EszipV2::resolve_redirect`]. The chains
+/// start with the requested specifier, or the one it matches with
+/// [`crate::EszipV2::set_lookup_normalization`].
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RedirectError {
+  #[error("module '{0}' not found")]
+  NotFound(String),
+  #[error(
+    "redirect to '{}', which is not in the eszip: {}",
+    .0.last().unwrap(),
+    .0.join(" -> ")
+  )]
+  MissingTarget(Vec<String>),
+  /// The chain ends with the first specifier that is visited twice.
+  #[error("redirects form a cycle: {}", .0.join(" -> "))]
+  Cycle(Vec<String>),
+}
+
+/// An error returned by [`crate::EszipV2::parse_import_map`].
+#[derive(Debug, Error)]
+pub enum ImportMapError {
+  #[error("import map '{0}' not found")]
+  NotFound(String),
+  #[error("source of import map '{0}' is not available")]
+  SourceUnavailable(String),
+  #[error("invalid import map '{0}': {1}")]
+  Invalid(String, String),
+  #[error(
+    "import map entry '{key}' points to '{target}', which is not in the eszip"
+  )]
+  MissingTarget { key: String, target: String },
+}
+
+/// An error returned by [`crate::bundle::to_single_module`].
+#[derive(Debug, Error)]
+pub enum BundleError {
+  #[error("module '{0}' not found")]
+  NotFound(String),
+  #[error("could not resolve '{specifier}' from '{referrer}'")]
+  Unresolved { specifier: String, referrer: String },
+  #[error("source of module '{0}' is not available")]
+  SourceUnavailable(String),
+  #[error("module '{0}' of kind {1:?} can not be bundled")]
+  Unsupported(String, crate::ModuleKind),
+  #[error("failed to parse '{0}': {1}")]
+  Parse(String, String),
+  #[error("module '{specifier}' does not export '{name}'")]
+  MissingExport { specifier: String, name: String },
+  #[error(transparent)]
+  ImportMap(#[from] crate::ImportMapError),
+}
@@ -3,14 +3,8 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use deno_ast::EmitOptions;
-use deno_ast::TranspileOptions;
 use deno_graph::source::CacheSetting;
 use deno_graph::source::ResolveError;
-use deno_graph::BuildOptions;
-use deno_graph::CapturingModuleAnalyzer;
-use deno_graph::GraphKind;
-use deno_graph::ModuleGraph;
 use import_map::ImportMap;
 use reqwest::StatusCode;
 use url::Url;
@@ -23,7 +17,7 @@ async fn main() {
   let out = args.get(2).unwrap();
   let maybe_import_map = args.get(3).map(|url| Url::parse(url).unwrap());
 
-  let loader = Loader;
+  let loader = eszip::loader::CompositeLoader::new().with_remote(HttpLoader);
   let (maybe_import_map, maybe_import_map_data) =
     if let Some(import_map_url) = maybe_import_map {
       let resp = deno_graph::source::Loader::load(
@@ -54,31 +48,16 @@ async fn main() {
       (None, None)
     };
 
-  let analyzer = CapturingModuleAnalyzer::default();
-
-  let mut graph = ModuleGraph::new(GraphKind::CodeOnly);
-  graph
-    .build(
-      vec![url],
-      &loader,
-      BuildOptions {
-        resolver: Some(&Resolver(maybe_import_map)),
-        module_analyzer: &analyzer,
-        ..Default::default()
-      },
-    )
-    .await;
-
-  graph.valid().unwrap();
-
-  let mut eszip = eszip::EszipV2::from_graph(eszip::FromGraphOptions {
-    graph,
-    parser: analyzer.as_capturing_parser(),
-    transpile_options: TranspileOptions::default(),
-    emit_options: EmitOptions::default(),
-    relative_file_base: None,
-    npm_packages: None,
-  })
+  let resolver = Resolver(maybe_import_map);
+  let mut eszip = eszip::EszipV2::build(
+    vec![url],
+    &loader,
+    eszip::BuildEszipOptions {
+      resolver: Some(&resolver),
+      ..Default::default()
+    },
+  )
+  .await
   .unwrap();
   if let Some((import_map_specifier, import_map_content)) =
     maybe_import_map_data
@@ -121,9 +100,9 @@ impl deno_graph::source::Resolver for Resolver {
   }
 }
 
-struct Loader;
+struct HttpLoader;
 
-impl deno_graph::source::Loader for Loader {
+impl deno_graph::source::Loader for HttpLoader {
   fn load(
     &self,
     specifier: &deno_graph::ModuleSpecifier,
@@ -132,47 +111,29 @@ impl deno_graph::source::Loader for Loader {
     let specifier = specifier.clone();
 
     Box::pin(async move {
-      match specifier.scheme() {
-        "data" => deno_graph::source::load_data_url(&specifier),
-        "file" => {
-          let path = std::fs::canonicalize(specifier.to_file_path().unwrap())?;
-          let content = std::fs::read(&path)?;
-          Ok(Some(deno_graph::source::LoadResponse::Module {
-            specifier: Url::from_file_path(&path).unwrap(),
-            maybe_headers: None,
-            content: Arc::from(content),
-          }))
-        }
-        "http" | "https" => {
-          let resp = reqwest::get(specifier.as_str()).await?;
-          if resp.status() == StatusCode::NOT_FOUND {
-            Ok(None)
-          } else {
-            let resp = resp.error_for_status()?;
-            let mut headers = HashMap::new();
-            for key in resp.headers().keys() {
-              let key_str = key.to_string();
-              let values = resp.headers().get_all(key);
-              let values_str = values
-                .iter()
-                .filter_map(|e| e.to_str().ok())
-                .collect::<Vec<&str>>()
-                .join(",");
-              headers.insert(key_str, values_str);
-            }
-            let url = resp.url().clone();
-            let content = resp.bytes().await?;
-            Ok(Some(deno_graph::source::LoadResponse::Module {
-              specifier: url,
-              maybe_headers: Some(headers),
-              content: Arc::from(content.as_ref()),
-            }))
-          }
+      let resp = reqwest::get(specifier.as_str()).await?;
+      if resp.status() == StatusCode::NOT_FOUND {
+        Ok(None)
+      } else {
+        let resp = resp.error_for_status()?;
+        let mut headers = HashMap::new();
+        for key in resp.headers().keys() {
+          let key_str = key.to_string();
+          let values = resp.headers().get_all(key);
+          let values_str = values
+            .iter()
+            .filter_map(|e| e.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join(",");
+          headers.insert(key_str, values_str);
         }
-        _ => Err(anyhow::anyhow!(
-          "unsupported scheme: {}",
-          specifier.scheme()
-        )),
+        let url = resp.url().clone();
+        let content = resp.bytes().await?;
+        Ok(Some(deno_graph::source::LoadResponse::Module {
+          specifier: url,
+          maybe_headers: Some(headers),
+          content: Arc::from(content.as_ref()),
+        }))
       }
     })
   }
@@ -0,0 +1,759 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use std::sync::Arc;
+
+use eszip::deno_graph;
+use eszip::deno_graph::source::ResolveError;
+use eszip::deno_graph::ModuleSpecifier;
+use eszip::extract::ExtractOptions;
+use eszip::loader::CompositeLoader;
+use eszip::v2::Checksum;
+use eszip::v2::ModuleStats;
+use eszip::validate::ValidateOptions;
+use eszip::BuildEszipOptions;
+use eszip::EszipV2;
+use eszip::FromGraphConfig;
+use eszip::ModuleFlags;
+use eszip::ModuleKind;
+use futures::io::AllowStdIo;
+use futures::io::BufReader;
+use futures::StreamExt;
+use import_map::ImportMap;
+use url::Url;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+  let mut args = env::args().skip(1);
+  let result = match args.next().as_deref() {
+    Some("build") => build(args.collect()).await,
+    Some("list") => list(args.collect()).await,
+    Some("show") => show(args.collect()).await,
+    Some("extract") => extract(args.collect()).await,
+    Some("bundle") => bundle(args.collect()).await,
+    Some("checksum") => checksum(args.collect()).await,
+    Some("optimize") => optimize(args.collect()).await,
+    Some("validate") => validate(args.collect()).await,
+    Some("check") => check(args.collect()).await,
+    Some("grep") => grep(args.collect()).await,
+    Some("stats") => stats(args.collect()).await,
+    Some("diff") => diff(args.collect()).await,
+    Some(command) => Err(format!("Unknown command: {command}")),
+    None => Err("Missing command".to_string()),
+  };
+  if let Err(err) = result {
+    eprintln!("Error: {}", err);
+    print_help();
+    std::process::exit(1);
+  }
+}
+
+fn print_help() {
+  println!("Usage:");
+  println!(
+    "  eszip build <entry> <output_path> [--import-map <path>] \
+     [--resolve-imports] [--checksum <checksum>]"
+  );
+  println!("  eszip list <eszip_path> [--sizes]");
+  println!("  eszip show <eszip_path> <specifier> [--source-map]");
+  println!(
+    "  eszip extract <eszip_path> <output_dir> [--skip-source-maps] \
+     [--npm-lockfile] [--relative-file-base <url>]"
+  );
+  println!("  eszip bundle <eszip_path> <root> <output_path>");
+  println!("  eszip checksum <eszip_path> --set <checksum> -o <output_path>");
+  println!(
+    "  eszip optimize <eszip_path> -o <output_path> [--strip-source-maps]"
+  );
+  println!(
+    "  eszip validate [--strict] [--root <specifier>]... \
+     [--import-map <specifier>] <eszip_path>"
+  );
+  println!(
+    "  eszip check [--root <specifier>]... [--import-map <specifier>] \
+     <eszip_path>"
+  );
+  println!("  eszip grep <eszip_path> <pattern>");
+  println!("  eszip stats <eszip_path> [--top <n>]");
+  println!("  eszip diff <old_eszip_path> <new_eszip_path> [--json]");
+  println!();
+  println!("Checksums: {}", checksum_names().join(", "));
+}
+
+/// Build an archive from a local entry module and the modules it imports,
+/// optionally resolving the imports with an import map that is embedded in
+/// the archive. With `--resolve-imports`, the resolved specifiers are written
+/// into the sources of the modules instead of embedding the import map.
+async fn build(args: Vec<String>) -> Result<(), String> {
+  let mut entry = None;
+  let mut output_path = None;
+  let mut import_map_path = None;
+  let mut resolve_imports = false;
+  let mut checksum = None;
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--import-map" => {
+        import_map_path = Some(PathBuf::from(
+          args_iter.next().ok_or("Missing import map path")?,
+        ));
+      }
+      "--resolve-imports" => resolve_imports = true,
+      "--checksum" => {
+        let name = args_iter.next().ok_or("Missing checksum")?;
+        checksum = Some(parse_checksum(&name)?);
+      }
+      _ if entry.is_none() => {
+        entry = Some(arg);
+      }
+      _ if output_path.is_none() => {
+        output_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let entry = resolve_url(&entry.ok_or("Missing entry module")?)?;
+  let output_path = output_path.ok_or("Missing output path")?;
+
+  let import_map = match import_map_path {
+    Some(path) => {
+      let url = resolve_url(&path.to_string_lossy())?;
+      let content = fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+      let import_map = import_map::parse_from_json(url.clone(), &content)
+        .map_err(|err| format!("Invalid import map {url}: {err}"))?
+        .import_map;
+      Some((import_map, url, content))
+    }
+    None => None,
+  };
+
+  let resolver =
+    ImportMapResolver(import_map.as_ref().map(|(import_map, ..)| import_map));
+  let mut eszip = EszipV2::build(
+    vec![entry.clone()],
+    &CompositeLoader::new(),
+    BuildEszipOptions {
+      resolver: Some(&resolver),
+      config: FromGraphConfig {
+        resolve_import_specifiers: resolve_imports,
+        ..Default::default()
+      },
+      ..Default::default()
+    },
+  )
+  .await
+  .map_err(|err| format!("Failed to build {entry}: {err:#}"))?;
+  if let Some((_, url, content)) = import_map.filter(|_| !resolve_imports) {
+    eszip.add_import_map(
+      ModuleKind::Json,
+      url.to_string(),
+      Arc::from(content.into_bytes()),
+    );
+  }
+  if let Some(checksum) = checksum {
+    eszip.set_checksum(checksum);
+  }
+  let bytes = eszip.into_bytes();
+  fs::write(&output_path, &bytes).map_err(|err| {
+    format!("Failed to write {}: {err}", output_path.display())
+  })?;
+  println!("Wrote {} ({} bytes)", output_path.display(), bytes.len());
+  Ok(())
+}
+
+/// List the modules and redirects of an archive, without reading its data
+/// section. With `--sizes`, list the sizes and offsets of the modules
+/// instead.
+async fn list(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut sizes = false;
+  for arg in args {
+    match arg.as_str() {
+      "--sizes" => sizes = true,
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+
+  let file = fs::File::open(&eszip_path)
+    .map_err(|err| format!("Failed to open {}: {err}", eszip_path.display()))?;
+  let (eszip, _loader) = EszipV2::parse(BufReader::new(AllowStdIo::new(file)))
+    .await
+    .map_err(|err| {
+      format!("Failed to parse {}: {err}", eszip_path.display())
+    })?;
+  if sizes {
+    println!(
+      "{:<10} {:>10} {:>12} {:>10}  Specifier",
+      "Kind", "Source", "Source map", "Offset"
+    );
+    for info in eszip.module_infos() {
+      let offset = info.offset.map(|offset| offset.to_string());
+      println!(
+        "{:<10} {:>10} {:>12} {:>10}  {}",
+        format!("{:?}", info.kind).to_lowercase(),
+        info.source_len,
+        info.source_map_len,
+        offset.as_deref().unwrap_or("-"),
+        info.specifier
+      );
+    }
+    return Ok(());
+  }
+  for (specifier, module) in eszip.iter() {
+    if module.specifier != specifier {
+      println!("{:<10} {specifier} -> {}", "redirect", module.specifier);
+      continue;
+    }
+    let kind = format!("{:?}", module.kind).to_lowercase();
+    match module.source_len() {
+      Some(len) => println!("{kind:<10} {specifier} ({len} bytes)"),
+      None => println!("{kind:<10} {specifier}"),
+    }
+  }
+  Ok(())
+}
+
+/// Print the source, or the source map, of a module of an archive.
+async fn show(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut specifier = None;
+  let mut source_map = false;
+  for arg in args {
+    match arg.as_str() {
+      "--source-map" => {
+        source_map = true;
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ if specifier.is_none() => {
+        specifier = Some(arg);
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+  let specifier = specifier.ok_or("Missing specifier")?;
+
+  let eszip = load(&eszip_path).await?;
+  let module = eszip
+    .get_module(&specifier)
+    .or_else(|| eszip.get_import_map(&specifier))
+    .ok_or_else(|| format!("Module not found: {specifier}"))?;
+  let content = if source_map {
+    module.source_map().await
+  } else {
+    module.source().await
+  };
+  let content = content.ok_or_else(|| {
+    format!("The module {specifier} has no source stored in the archive")
+  })?;
+  println!("{}", String::from_utf8_lossy(&content));
+  Ok(())
+}
+
+/// Write the modules of an archive to a directory, see
+/// `eszip::EszipV2::extract_to_dir`.
+async fn extract(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut output_dir = None;
+  let mut options = ExtractOptions::default();
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--skip-source-maps" => {
+        options.skip_source_maps = true;
+      }
+      "--npm-lockfile" => {
+        options.npm_lockfile = true;
+      }
+      "--relative-file-base" => {
+        let base = args_iter.next().ok_or("Missing relative file base")?;
+        options.relative_file_base = Some(resolve_url(&base)?);
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ if output_dir.is_none() => {
+        output_dir = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+  let output_dir = output_dir.ok_or("Missing output directory")?;
+
+  let eszip = load(&eszip_path).await?;
+  let paths =
+    eszip
+      .extract_to_dir(&output_dir, options)
+      .await
+      .map_err(|err| {
+        format!("Failed to extract to {}: {err}", output_dir.display())
+      })?;
+  for path in paths {
+    println!("Extracted {}", path.display());
+  }
+  Ok(())
+}
+
+/// Flatten the modules of an archive into a single JavaScript module, see
+/// `eszip::bundle::to_single_module`.
+async fn bundle(args: Vec<String>) -> Result<(), String> {
+  let mut args_iter = args.into_iter();
+  let eszip_path = PathBuf::from(args_iter.next().ok_or("Missing eszip path")?);
+  let root = args_iter.next().ok_or("Missing root specifier")?;
+  let output_path =
+    PathBuf::from(args_iter.next().ok_or("Missing output path")?);
+  if let Some(arg) = args_iter.next() {
+    return Err(format!("Unknown argument: {}", arg));
+  }
+
+  let eszip = load(&eszip_path).await?;
+  let bundle = eszip::bundle::to_single_module(&eszip, &root)
+    .await
+    .map_err(|err| format!("Failed to bundle {root}: {err}"))?;
+  fs::write(&output_path, bundle).map_err(|err| {
+    format!("Failed to write {}: {err}", output_path.display())
+  })?;
+  Ok(())
+}
+
+/// Re-hash an archive with a different hash function, without rebuilding it.
+async fn checksum(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut checksum = None;
+  let mut output_path = None;
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--set" => {
+        let name = args_iter.next().ok_or("Missing checksum")?;
+        checksum = Some(parse_checksum(&name)?);
+      }
+      "--output" | "-o" => {
+        output_path = Some(PathBuf::from(
+          args_iter.next().ok_or("Missing output path")?,
+        ));
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+  let checksum = checksum.ok_or("Missing --set <checksum>")?;
+  let output_path = output_path.ok_or("Missing output path")?;
+
+  let mut eszip = load(&eszip_path).await?;
+  eszip.rehash(checksum);
+  fs::write(&output_path, eszip.into_bytes()).map_err(|err| {
+    format!("Failed to write {}: {err}", output_path.display())
+  })?;
+  println!("Wrote {}", output_path.display());
+  Ok(())
+}
+
+/// Deduplicate identical sources, flatten redirects and reorder the modules
+/// for a faster cold start, optionally stripping source maps.
+async fn optimize(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut output_path = None;
+  let mut strip_source_maps = false;
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--strip-source-maps" => {
+        strip_source_maps = true;
+      }
+      "--output" | "-o" => {
+        output_path = Some(PathBuf::from(
+          args_iter.next().ok_or("Missing output path")?,
+        ));
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+  let output_path = output_path.ok_or("Missing output path")?;
+
+  let mut eszip = load(&eszip_path).await?;
+  if strip_source_maps {
+    eszip.strip_source_maps();
+  }
+  eszip.flatten_redirects();
+  eszip.sort_for_cold_start();
+  eszip.set_deduplicate_sources(true);
+  let before = fs::metadata(&eszip_path).map(|m| m.len()).unwrap_or(0);
+  let bytes = eszip.into_bytes();
+  fs::write(&output_path, &bytes).map_err(|err| {
+    format!("Failed to write {}: {err}", output_path.display())
+  })?;
+  println!(
+    "Wrote {} ({} -> {} bytes)",
+    output_path.display(),
+    before,
+    bytes.len()
+  );
+  Ok(())
+}
+
+/// Check that an archive can be parsed and that its checksums are valid. With
+/// `--strict`, also run the structural checks of `eszip::validate::validate` and
+/// print the findings as JSON.
+async fn validate(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut strict = false;
+  let mut options = ValidateOptions::default();
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--strict" => {
+        strict = true;
+      }
+      "--root" => {
+        let root = args_iter.next().ok_or("Missing root specifier")?;
+        let root = ModuleSpecifier::parse(&root)
+          .map_err(|err| format!("Invalid root {root}: {err}"))?;
+        options.roots.push(root);
+      }
+      "--import-map" => {
+        options.import_map =
+          Some(args_iter.next().ok_or("Missing import map specifier")?);
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+
+  if !strict {
+    load(&eszip_path).await?;
+    println!("{} is valid", eszip_path.display());
+    return Ok(());
+  }
+  let bytes = fs::read(&eszip_path)
+    .map_err(|err| format!("Failed to read {}: {err}", eszip_path.display()))?;
+  let findings =
+    eszip::validate::validate(&bytes, &options)
+      .await
+      .map_err(|err| {
+        format!("Failed to parse {}: {err}", eszip_path.display())
+      })?;
+  println!("{}", serde_json::to_string_pretty(&findings).unwrap());
+  if !findings.is_empty() {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Check the integrity of the modules of an archive, see
+/// [`EszipV2::integrity_check`], and print the findings.
+async fn check(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut options = ValidateOptions::default();
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--root" => {
+        let root = args_iter.next().ok_or("Missing root specifier")?;
+        let root = ModuleSpecifier::parse(&root)
+          .map_err(|err| format!("Invalid root {root}: {err}"))?;
+        options.roots.push(root);
+      }
+      "--import-map" => {
+        options.import_map =
+          Some(args_iter.next().ok_or("Missing import map specifier")?);
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+
+  let eszip = load(&eszip_path).await?;
+  let findings = eszip.integrity_check(&options).await;
+  println!("{}", serde_json::to_string_pretty(&findings).unwrap());
+  if !findings.is_empty() {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Print the lines of the module sources that contain a pattern, as
+/// `specifier:line:content`. Sources are scanned as they are read from the
+/// archive, and released right after.
+async fn grep(args: Vec<String>) -> Result<(), String> {
+  let mut args_iter = args.into_iter();
+  let eszip_path = PathBuf::from(args_iter.next().ok_or("Missing eszip path")?);
+  let pattern = args_iter.next().ok_or("Missing pattern")?;
+  if let Some(arg) = args_iter.next() {
+    return Err(format!("Unknown argument: {}", arg));
+  }
+  if pattern.is_empty() {
+    return Err("The pattern must not be empty".to_string());
+  }
+
+  let file = fs::File::open(&eszip_path)
+    .map_err(|err| format!("Failed to open {}: {err}", eszip_path.display()))?;
+  let modules = EszipV2::parse_stream(BufReader::new(AllowStdIo::new(file)));
+  futures::pin_mut!(modules);
+  let mut matched = false;
+  while let Some(result) = modules.next().await {
+    let (specifier, module) = result.map_err(|err| {
+      format!("Failed to parse {}: {err}", eszip_path.display())
+    })?;
+    // the sources of external modules are not stored in the archive
+    if module.flags().contains(ModuleFlags::EXTERNAL) {
+      continue;
+    }
+    let Some(source) = module.take_source().await else {
+      continue;
+    };
+    for (index, line) in source.split(|byte| *byte == b'\n').enumerate() {
+      if line
+        .windows(pattern.len())
+        .any(|window| window == pattern.as_bytes())
+      {
+        matched = true;
+        let line = String::from_utf8_lossy(line);
+        println!("{specifier}:{}:{}", index + 1, line.trim_end_matches('\r'));
+      }
+    }
+  }
+  if !matched {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+/// Summarize the size of an archive per module kind, and list its largest
+/// modules and source maps.
+async fn stats(args: Vec<String>) -> Result<(), String> {
+  let mut eszip_path = None;
+  let mut top = 10;
+  let mut args_iter = args.into_iter();
+  while let Some(arg) = args_iter.next() {
+    match arg.as_str() {
+      "--top" => {
+        let n = args_iter.next().ok_or("Missing number of modules")?;
+        top = n.parse().map_err(|_| format!("Invalid number: {n}"))?;
+      }
+      _ if eszip_path.is_none() => {
+        eszip_path = Some(PathBuf::from(arg));
+      }
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let eszip_path = eszip_path.ok_or("Missing eszip path")?;
+
+  let size = fs::metadata(&eszip_path)
+    .map_err(|err| format!("Failed to open {}: {err}", eszip_path.display()))?
+    .len();
+  let file = fs::File::open(&eszip_path)
+    .map_err(|err| format!("Failed to open {}: {err}", eszip_path.display()))?;
+  // the sizes are known from the header, the data section is not read
+  let (eszip, _loader) = EszipV2::parse(BufReader::new(AllowStdIo::new(file)))
+    .await
+    .map_err(|err| {
+      format!("Failed to parse {}: {err}", eszip_path.display())
+    })?;
+  let stats = eszip.stats();
+
+  println!("Total size: {size} bytes");
+  println!(
+    "Modules: {} ({} bytes of sources, {} bytes of source maps)",
+    stats.modules.len(),
+    stats.source_bytes(),
+    stats.source_map_bytes()
+  );
+  println!("Redirects: {}", stats.redirects);
+  println!();
+  println!(
+    "{:<12} {:>8} {:>14} {:>14}",
+    "Kind", "Modules", "Sources", "Source maps"
+  );
+  for kind in &stats.kinds {
+    println!(
+      "{:<12} {:>8} {:>14} {:>14}",
+      format!("{:?}", kind.kind),
+      kind.modules,
+      kind.source_bytes,
+      kind.source_map_bytes
+    );
+  }
+  for (title, modules, len) in [
+    (
+      "Largest sources",
+      stats.largest_sources(top),
+      (|module| module.source_len) as fn(&ModuleStats) -> usize,
+    ),
+    (
+      "Largest source maps",
+      stats.largest_source_maps(top),
+      |module| module.source_map_len,
+    ),
+  ] {
+    if modules.is_empty() {
+      continue;
+    }
+    println!();
+    println!("{title}:");
+    for module in modules {
+      println!("{:>14} {}", len(module), module.specifier);
+    }
+  }
+  Ok(())
+}
+
+/// Parse an archive, including its data section.
+/// Print the differences between two archives, and exit with a non-zero
+/// status if there are any.
+async fn diff(args: Vec<String>) -> Result<(), String> {
+  let mut paths = Vec::new();
+  let mut json = false;
+  for arg in args {
+    match arg.as_str() {
+      "--json" => json = true,
+      _ if paths.len() < 2 => paths.push(PathBuf::from(arg)),
+      _ => return Err(format!("Unknown argument: {}", arg)),
+    }
+  }
+  let [old_path, new_path] = <[PathBuf; 2]>::try_from(paths)
+    .map_err(|_| "Missing eszip paths".to_string())?;
+  let old = load(&old_path).await?;
+  let new = load(&new_path).await?;
+  let diff = eszip::diff(&old, &new);
+
+  if json {
+    println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+  } else {
+    for entry in &diff.added {
+      println!("+ {}", entry.specifier);
+    }
+    for entry in &diff.removed {
+      println!("- {}", entry.specifier);
+    }
+    for entry in &diff.changed {
+      println!("~ {}", entry.specifier);
+    }
+    for root in &diff.npm.root_packages {
+      let none = "(none)".to_string();
+      println!(
+        "~ npm:{} {} -> {}",
+        root.req,
+        root.before.as_ref().unwrap_or(&none),
+        root.after.as_ref().unwrap_or(&none)
+      );
+    }
+    for id in &diff.npm.added_packages {
+      println!("+ npm package {id}");
+    }
+    for id in &diff.npm.removed_packages {
+      println!("- npm package {id}");
+    }
+    if let Some(options) = &diff.options {
+      println!("~ options {:?} -> {:?}", options.before, options.after);
+    }
+  }
+  if !diff.is_empty() {
+    std::process::exit(1);
+  }
+  Ok(())
+}
+
+async fn load(path: &Path) -> Result<EszipV2, String> {
+  let file = fs::File::open(path)
+    .map_err(|err| format!("Failed to open {}: {err}", path.display()))?;
+  let bufreader = BufReader::new(AllowStdIo::new(file));
+  let (eszip, loader) = EszipV2::parse(bufreader)
+    .await
+    .map_err(|err| format!("Failed to parse {}: {err}", path.display()))?;
+  loader
+    .await
+    .map_err(|err| format!("Failed to load {}: {err}", path.display()))?;
+  Ok(eszip)
+}
+
+/// Parse a URL, or resolve a path against the current directory. Paths of
+/// directories are resolved with a trailing slash.
+fn resolve_url(url_or_path: &str) -> Result<Url, String> {
+  if let Ok(url) = Url::parse(url_or_path) {
+    // Windows paths like `C:\main.ts` parse as URLs with a `c:` scheme
+    if url.scheme().len() > 1 {
+      return Ok(url);
+    }
+  }
+  let path = fs::canonicalize(url_or_path)
+    .map_err(|err| format!("Failed to resolve {url_or_path}: {err}"))?;
+  let url = if path.is_dir() {
+    Url::from_directory_path(&path)
+  } else {
+    Url::from_file_path(&path)
+  };
+  url.map_err(|_| format!("Invalid path: {}", path.display()))
+}
+
+/// Resolves imports with an import map, if any.
+#[derive(Debug)]
+struct ImportMapResolver<'a>(Option<&'a ImportMap>);
+
+impl deno_graph::source::Resolver for ImportMapResolver<'_> {
+  fn resolve(
+    &self,
+    specifier: &str,
+    referrer_range: &deno_graph::Range,
+    _kind: deno_graph::source::ResolutionKind,
+  ) -> Result<ModuleSpecifier, ResolveError> {
+    match self.0 {
+      Some(import_map) => import_map
+        .resolve(specifier, &referrer_range.specifier)
+        .map_err(|err| ResolveError::Other(err.into())),
+      None => Ok(deno_graph::resolve_import(
+        specifier,
+        &referrer_range.specifier,
+      )?),
+    }
+  }
+}
+
+fn parse_checksum(name: &str) -> Result<Checksum, String> {
+  match name {
+    "none" => Ok(Checksum::NoChecksum),
+    #[cfg(feature = "sha256")]
+    "sha256" => Ok(Checksum::Sha256),
+    #[cfg(feature = "xxhash3")]
+    "xxhash3" => Ok(Checksum::XxHash3),
+    _ => Err(format!(
+      "Unsupported checksum: {name} (expected one of {})",
+      checksum_names().join(", ")
+    )),
+  }
+}
+
+fn checksum_names() -> Vec<&'static str> {
+  vec![
+    "none",
+    #[cfg(feature = "sha256")]
+    "sha256",
+    #[cfg(feature = "xxhash3")]
+    "xxhash3",
+  ]
+}
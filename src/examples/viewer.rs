@@ -1,9 +1,9 @@
 // Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
 
+use eszip::extract::ExtractOptions;
 use futures::io::AllowStdIo;
 use futures::io::BufReader;
 use std::env;
-use std::fs;
 use std::path::PathBuf;
 
 #[tokio::main(flavor = "current_thread")]
@@ -22,46 +22,39 @@ async fn main() {
   let bufreader = BufReader::new(AllowStdIo::new(file));
   let (eszip, loader) = eszip::EszipV2::parse(bufreader).await.unwrap();
 
+  if let Some(output_dir) = output_dir {
+    let extract = async {
+      eszip
+        .extract_to_dir(&output_dir, ExtractOptions::default())
+        .await
+        .map_err(eszip::ParseError::Io)
+    };
+    let (_, paths) = tokio::try_join!(loader, extract).unwrap();
+    for path in paths {
+      println!("Extracted {}", path.display());
+    }
+    return;
+  }
+
   let fut = async move {
     for (specifier, module) in eszip {
       if module.specifier == specifier {
-        // skip extracting data specifiers.
-        if specifier.starts_with("data:") {
-          continue;
-        }
         let source = module.source().await.expect("source already taken");
         let source = std::str::from_utf8(&source).unwrap();
 
-        if let Some(ref output_dir) = output_dir {
-          let specifier = specifier
-            .trim_start_matches("file:///")
-            .trim_start_matches("http://")
-            .trim_start_matches("https://");
-          let file_path = output_dir.join(
-            PathBuf::from(&specifier)
-              .strip_prefix("/")
-              .unwrap_or(&PathBuf::from(&specifier)),
-          );
-          if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent).expect("Failed to create directory");
-          }
-          fs::write(&file_path, source).expect("Failed to write file");
-          println!("Extracted {}", file_path.display());
-        } else {
-          println!("Specifier: {specifier}",);
-          println!("Kind: {kind:?}", kind = module.kind);
-          println!("---");
-          println!("{source}");
-
-          let source_map = module.source_map().await;
-          if let Some(source_map) = source_map {
-            let source_map = std::str::from_utf8(&source_map).unwrap();
-            println!("---");
-            println!("{source_map}");
-          }
+        println!("Specifier: {specifier}",);
+        println!("Kind: {kind:?}", kind = module.kind);
+        println!("---");
+        println!("{source}");
 
-          println!("============");
+        let source_map = module.source_map().await;
+        if let Some(source_map) = source_map {
+          let source_map = std::str::from_utf8(&source_map).unwrap();
+          println!("---");
+          println!("{source_map}");
         }
+
+        println!("============");
       }
     }
 
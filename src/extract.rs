@@ -0,0 +1,320 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Extracting the modules of an eszip to a directory, see
+//! [`crate::EszipV2::extract_to_dir`].
+
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use deno_npm::resolution::ValidSerializedNpmResolutionSnapshot;
+use url::Url;
+
+use crate::lockfile::npm_snapshot_lockfile;
+use crate::EszipV2;
+use crate::ModuleFlags;
+
+/// Options for [`crate::EszipV2::extract_to_dir`].
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+  /// Extract the local modules that are descendants of this `file:` URL
+  /// relative to the directory, like the modules of an archive built with
+  /// [`crate::FromGraphOptions::relative_file_base`].
+  pub relative_file_base: Option<Url>,
+  /// Do not write the source maps of the modules.
+  pub skip_source_maps: bool,
+  /// Also write the npm resolution snapshot of the archive, if any, as a
+  /// `deno.lock` at the root of the directory. See
+  /// [`crate::lockfile::npm_snapshot_lockfile`].
+  pub npm_lockfile: bool,
+}
+
+pub(crate) async fn extract_to_dir(
+  eszip: &EszipV2,
+  npm_snapshot: Option<&ValidSerializedNpmResolutionSnapshot>,
+  dir: &Path,
+  options: &ExtractOptions,
+) -> io::Result<Vec<PathBuf>> {
+  let mut written = Vec::new();
+  let mut paths = HashSet::new();
+  for (specifier, module) in eszip.iter() {
+    // redirects are extracted as their target
+    if module.specifier != specifier
+      || module.flags().contains(ModuleFlags::EXTERNAL)
+    {
+      continue;
+    }
+    let Some(relative_path) =
+      specifier_path(&specifier, options.relative_file_base.as_ref())?
+    else {
+      continue;
+    };
+    if !paths.insert(relative_path.clone()) {
+      return Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{specifier} is extracted to the path of another module"),
+      ));
+    }
+    let Some(source) = module.source().await else {
+      return Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("the source of {specifier} is not available"),
+      ));
+    };
+
+    let path = dir.join(&relative_path);
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, &source)?;
+    written.push(path.clone());
+
+    if options.skip_source_maps {
+      continue;
+    }
+    match module.source_map().await {
+      Some(source_map) if !source_map.is_empty() => {
+        let mut map_path = path.into_os_string();
+        map_path.push(".map");
+        std::fs::write(&map_path, &source_map)?;
+        written.push(map_path.into());
+      }
+      _ => {}
+    }
+  }
+
+  if let Some(snapshot) = npm_snapshot.filter(|_| options.npm_lockfile) {
+    let path = dir.join("deno.lock");
+    let lockfile = npm_snapshot_lockfile(path.clone(), snapshot);
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, lockfile.as_json_string())?;
+    written.push(path);
+  }
+  Ok(written)
+}
+
+/// The path, relative to the extraction directory, that the module with
+/// `specifier` is extracted to, or `None` if the module is not extracted.
+///
+/// Specifiers that are not URLs, like the relative specifiers of archives
+/// built with a relative file base, keep their path. `file:` URLs are
+/// extracted below `file/`, and remote URLs below a directory named after
+/// their scheme, host and port, e.g. `https/deno.land_8080/`. The query of a
+/// URL is appended to its last segment. `data:` and `blob:` modules are not
+/// extracted.
+fn specifier_path(
+  specifier: &str,
+  relative_file_base: Option<&Url>,
+) -> io::Result<Option<PathBuf>> {
+  let mut segments = Vec::new();
+  match Url::parse(specifier) {
+    Ok(url) => match url.scheme() {
+      "data" | "blob" => return Ok(None),
+      "file" => {
+        let relative = relative_file_base
+          .and_then(|base| base.make_relative(&url))
+          .filter(|relative| !relative.starts_with("../"));
+        if let Some(relative) = relative {
+          return relative_specifier_path(specifier, &relative).map(Some);
+        }
+        segments.push("file".to_string());
+        segments.extend(
+          url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .map(sanitize_segment),
+        );
+      }
+      scheme => {
+        let mut host = url.host_str().unwrap_or_default().to_string();
+        if let Some(port) = url.port() {
+          host = format!("{host}_{port}");
+        }
+        segments.push(sanitize_segment(scheme));
+        segments.push(sanitize_segment(&host));
+        segments.extend(
+          url
+            .path_segments()
+            .into_iter()
+            .flatten()
+            .map(sanitize_segment),
+        );
+        if let Some(query) = url.query() {
+          let last = segments.last_mut().unwrap();
+          last.push('_');
+          last.push_str(&sanitize_segment(query));
+        }
+      }
+    },
+    Err(_) => return relative_specifier_path(specifier, specifier).map(Some),
+  }
+  // directories are extracted as their index
+  if segments.last().is_some_and(|segment| segment.is_empty()) {
+    segments.pop();
+    segments.push("index".to_string());
+  }
+  Ok(Some(
+    segments.into_iter().filter(|s| !s.is_empty()).collect(),
+  ))
+}
+
+/// Convert a relative specifier to a path, refusing paths that would escape
+/// the extraction directory.
+fn relative_specifier_path(
+  specifier: &str,
+  relative: &str,
+) -> io::Result<PathBuf> {
+  let mut path = PathBuf::new();
+  for segment in relative.split(['/', '\\']) {
+    match segment {
+      "" | "." => {}
+      ".." => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("{specifier} would be extracted outside of the directory"),
+        ));
+      }
+      segment => path.push(sanitize_segment(segment)),
+    }
+  }
+  if path.as_os_str().is_empty() {
+    path.push("index");
+  }
+  Ok(path)
+}
+
+/// Replace the characters of a path segment that are not safe to use in file
+/// names on all platforms.
+fn sanitize_segment(segment: &str) -> String {
+  segment
+    .chars()
+    .map(|c| {
+      if c.is_alphanumeric() || "-_.~@+%,=".contains(c) {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use deno_npm::resolution::SerializedNpmResolutionSnapshot;
+  use deno_npm::resolution::SerializedNpmResolutionSnapshotPackage;
+  use deno_npm::NpmPackageId;
+  use deno_semver::package::PackageReq;
+
+  use super::*;
+  use crate::ModuleKind;
+
+  fn path(specifier: &str) -> Option<String> {
+    let base = Url::parse("file:///app/").unwrap();
+    specifier_path(specifier, Some(&base))
+      .unwrap()
+      .map(|path| path.to_string_lossy().replace('\\', "/"))
+  }
+
+  #[test]
+  fn specifier_paths() {
+    assert_eq!(path("file:///app/src/main.ts").unwrap(), "src/main.ts");
+    assert_eq!(path("file:///lib/a.ts").unwrap(), "file/lib/a.ts");
+    assert_eq!(path("file:///C:/a%20b.ts").unwrap(), "file/C_/a%20b.ts");
+    assert_eq!(
+      path("https://deno.land:8080/std/mod.ts?v=1&x").unwrap(),
+      "https/deno.land_8080/std/mod.ts_v=1_x"
+    );
+    assert_eq!(
+      path("https://example.com/").unwrap(),
+      "https/example.com/index"
+    );
+    assert_eq!(path("main.ts").unwrap(), "main.ts");
+    assert_eq!(path("./src//a.ts").unwrap(), "src/a.ts");
+    assert_eq!(path("data:text/plain,1"), None);
+    let err = specifier_path("../etc/passwd", None).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[tokio::test]
+  async fn extract_to_dir() {
+    let dir = std::env::temp_dir()
+      .join(format!("eszip_extract_to_dir_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut eszip = EszipV2::default();
+    eszip.add_module(
+      ModuleKind::JavaScript,
+      "main.js".to_string(),
+      b"import 'https://example.com/a.js';".to_vec(),
+      b"{}".to_vec(),
+    );
+    eszip.add_module(
+      ModuleKind::JavaScript,
+      "https://example.com/a.js".to_string(),
+      b"export {};".to_vec(),
+      b"".to_vec(),
+    );
+    eszip.add_module(
+      ModuleKind::JavaScript,
+      "data:text/javascript,1".to_string(),
+      b"1".to_vec(),
+      b"".to_vec(),
+    );
+    eszip.add_npm_snapshot(
+      SerializedNpmResolutionSnapshot {
+        root_packages: [(
+          PackageReq::from_str("chalk@5").unwrap(),
+          NpmPackageId::from_serialized("chalk@5.3.0").unwrap(),
+        )]
+        .into_iter()
+        .collect(),
+        packages: vec![SerializedNpmResolutionSnapshotPackage {
+          id: NpmPackageId::from_serialized("chalk@5.3.0").unwrap(),
+          system: Default::default(),
+          dist: Default::default(),
+          dependencies: Default::default(),
+          optional_dependencies: Default::default(),
+          bin: None,
+          scripts: Default::default(),
+          deprecated: Default::default(),
+        }],
+      }
+      .into_valid()
+      .unwrap(),
+    );
+
+    let written = eszip
+      .extract_to_dir(
+        &dir,
+        ExtractOptions {
+          npm_lockfile: true,
+          ..Default::default()
+        },
+      )
+      .await
+      .unwrap();
+    assert_eq!(
+      written,
+      vec![
+        dir.join("main.js"),
+        dir.join("main.js.map"),
+        dir.join("https/example.com/a.js"),
+        dir.join("deno.lock"),
+      ]
+    );
+    assert_eq!(
+      std::fs::read(dir.join("https/example.com/a.js")).unwrap(),
+      b"export {};"
+    );
+    assert_eq!(std::fs::read(dir.join("main.js.map")).unwrap(), b"{}");
+    let lockfile: serde_json::Value =
+      serde_json::from_slice(&std::fs::read(dir.join("deno.lock")).unwrap())
+        .unwrap();
+    assert_eq!(lockfile["specifiers"]["npm:chalk@5"], "5.3.0");
+    assert!(lockfile["npm"]["chalk@5.3.0"].is_object());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
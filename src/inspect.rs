@@ -0,0 +1,431 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Low level inspection of the structure of eszip archives.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use futures::io::AsyncRead;
+use futures::io::AsyncReadExt;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::ParseError;
+use crate::v2::parse_header_frames;
+use crate::v2::parse_npm_packages;
+use crate::v2::Checksum;
+use crate::v2::HeaderFrame;
+use crate::v2::Options;
+use crate::v2::Section;
+use crate::ConfigKind;
+use crate::EszipV2;
+use crate::ModuleDependency;
+use crate::ModuleKind;
+
+/// A structured description of the header sections of an eszip v2 archive, as
+/// they are stored in the archive. Offsets are absolute positions in the
+/// archive, except for the offsets of the module entries, which are relative
+/// to the start of the sources and source maps sections respectively.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeaderDump {
+  /// The magic of the archive, e.g. `ESZIP2.2`.
+  pub version: String,
+  pub options: OptionsDump,
+  /// Offset of the modules header section.
+  pub modules_header_offset: usize,
+  /// Length of the content of the modules header section.
+  pub modules_header_len: usize,
+  pub entries: Vec<EntryDump>,
+  /// The npm section, if the version of the archive supports it.
+  pub npm: Option<NpmSectionDump>,
+  /// Offset of the sources section. The data sections follow the header
+  /// sections.
+  pub sources_offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OptionsDump {
+  /// The hash function used to checksum the archive. `None` if the archive
+  /// uses a hash function that this version of the library does not know.
+  pub checksum: Option<Checksum>,
+  /// Size in bytes of the digests.
+  pub checksum_size: Option<u8>,
+  /// Alignment in bytes of the positions of the sources in the archive, if
+  /// they are padded to one.
+  pub source_alignment: Option<usize>,
+  /// Whether the offsets and lengths of the sources and source maps are
+  /// stored in 64 bits.
+  #[serde(default)]
+  pub wide_offsets: bool,
+  /// The hash function of the digest of the whole archive, if it has one.
+  #[serde(default)]
+  pub archive_checksum: Option<Checksum>,
+}
+
+impl OptionsDump {
+  pub(crate) fn new(options: Options) -> Self {
+    Self {
+      checksum: options.checksum,
+      checksum_size: options.checksum_size(),
+      source_alignment: options.source_alignment(),
+      wide_offsets: options.wide_offsets,
+      archive_checksum: options.archive_checksum,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "entryKind")]
+pub enum EntryDump {
+  #[serde(rename_all = "camelCase")]
+  Module {
+    specifier: String,
+    kind: ModuleKind,
+    source_offset: u64,
+    source_len: u64,
+    source_map_offset: u64,
+    source_map_len: u64,
+    /// The bits of the [`crate::ModuleFlags`] of the module.
+    flags: u8,
+  },
+  Redirect {
+    specifier: String,
+    target: String,
+  },
+  #[serde(rename_all = "camelCase")]
+  NpmSpecifier {
+    specifier: String,
+    package_index: u32,
+  },
+  NpmWorkspaceLink {
+    specifier: String,
+    target: String,
+  },
+  NpmRegistry {
+    scope: String,
+    url: String,
+  },
+  Integrity {
+    specifier: String,
+    checksum: String,
+  },
+  External {
+    specifier: String,
+    kind: ModuleKind,
+    url: String,
+    /// The bits of the [`crate::ModuleFlags`] of the module.
+    flags: u8,
+  },
+  SourceChunk {
+    specifier: String,
+    offset: u64,
+    len: u64,
+  },
+  Dependencies {
+    specifier: String,
+    dependencies: Vec<ModuleDependency>,
+  },
+  Metadata {
+    key: String,
+    value: serde_json::Value,
+  },
+  OriginalSource {
+    specifier: String,
+    offset: u64,
+    len: u64,
+  },
+  Config {
+    specifier: String,
+    kind: ConfigKind,
+  },
+  Placeholder {
+    specifier: String,
+    kind: ModuleKind,
+    /// The bits of the [`crate::ModuleFlags`] of the module.
+    flags: u8,
+  },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmSectionDump {
+  /// Offset of the npm section.
+  pub offset: usize,
+  /// Length of the content of the npm section.
+  pub len: usize,
+  pub packages: Vec<NpmPackageDump>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NpmPackageDump {
+  /// The index of the package, as referenced by npm specifier entries and
+  /// package dependencies.
+  pub index: u32,
+  pub id: String,
+  pub dependencies: BTreeMap<String, u32>,
+}
+
+/// Decode the header sections (options, modules header and npm section) of
+/// an eszip v2 archive into a structured description, without reading the
+/// data sections. Checksums of the header sections are validated.
+pub async fn dump_header<R: AsyncRead + Unpin>(
+  reader: R,
+) -> Result<HeaderDump, ParseError> {
+  let mut reader = CountingReader {
+    inner: futures::io::BufReader::new(reader),
+    position: 0,
+  };
+
+  let mut magic = [0u8; 8];
+  reader.read_exact(&mut magic).await?;
+  if !EszipV2::has_magic(&magic) {
+    return Err(ParseError::InvalidV2);
+  }
+  let supports_npm = EszipV2::supports_npm(&magic);
+
+  let options = Options::read(&mut reader, &magic).await?;
+
+  let modules_header_offset = reader.position;
+  let modules_header = Section::read(&mut reader, options).await?;
+  if !modules_header.is_checksum_valid() {
+    return Err(ParseError::InvalidV2HeaderHash);
+  }
+  let entries =
+    parse_header_frames(modules_header.content(), supports_npm, options)?
+      .into_iter()
+      .map(|(specifier, frame)| match frame {
+        HeaderFrame::Module {
+          kind,
+          source_offset,
+          source_len,
+          source_map_offset,
+          source_map_len,
+          flags,
+        } => EntryDump::Module {
+          specifier,
+          kind,
+          source_offset,
+          source_len,
+          source_map_offset,
+          source_map_len,
+          flags: flags.bits(),
+        },
+        HeaderFrame::Redirect { target } => {
+          EntryDump::Redirect { specifier, target }
+        }
+        HeaderFrame::NpmSpecifier { package_index } => {
+          EntryDump::NpmSpecifier {
+            specifier,
+            package_index: package_index.0,
+          }
+        }
+        HeaderFrame::NpmWorkspaceLink { target } => {
+          EntryDump::NpmWorkspaceLink { specifier, target }
+        }
+        HeaderFrame::Integrity { checksum } => EntryDump::Integrity {
+          specifier,
+          checksum,
+        },
+        HeaderFrame::NpmRegistry { url } => EntryDump::NpmRegistry {
+          scope: specifier,
+          url: url.into(),
+        },
+        HeaderFrame::External { kind, url, flags } => EntryDump::External {
+          specifier,
+          kind,
+          url: url.into(),
+          flags: flags.bits(),
+        },
+        HeaderFrame::SourceChunk { offset, len } => EntryDump::SourceChunk {
+          specifier,
+          offset,
+          len,
+        },
+        HeaderFrame::Metadata { value } => EntryDump::Metadata {
+          key: specifier,
+          value,
+        },
+        HeaderFrame::Dependencies { dependencies } => EntryDump::Dependencies {
+          specifier,
+          dependencies,
+        },
+        HeaderFrame::OriginalSource { offset, len } => {
+          EntryDump::OriginalSource {
+            specifier,
+            offset,
+            len,
+          }
+        }
+        HeaderFrame::Config { kind } => EntryDump::Config { specifier, kind },
+        HeaderFrame::Placeholder { kind, flags } => EntryDump::Placeholder {
+          specifier,
+          kind,
+          flags: flags.bits(),
+        },
+      })
+      .collect();
+
+  let npm = if supports_npm {
+    let offset = reader.position;
+    let section = Section::read(&mut reader, options).await?;
+    if !section.is_checksum_valid() {
+      return Err(ParseError::InvalidV2NpmSnapshotHash);
+    }
+    let packages = parse_npm_packages(section.content())?
+      .into_iter()
+      .enumerate()
+      .map(|(index, package)| NpmPackageDump {
+        index: index as u32,
+        id: package.name,
+        dependencies: package
+          .dependencies
+          .into_iter()
+          .map(|(req, index)| (req, index.0))
+          .collect(),
+      })
+      .collect();
+    Some(NpmSectionDump {
+      offset,
+      len: section.content().len(),
+      packages,
+    })
+  } else {
+    None
+  };
+
+  Ok(HeaderDump {
+    version: String::from_utf8_lossy(&magic).into_owned(),
+    options: OptionsDump::new(options),
+    modules_header_offset,
+    modules_header_len: modules_header.content().len(),
+    entries,
+    npm,
+    sources_offset: reader.position,
+  })
+}
+
+/// Keeps track of the amount of bytes read from the inner reader.
+struct CountingReader<R> {
+  inner: R,
+  position: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut [u8],
+  ) -> Poll<std::io::Result<usize>> {
+    let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+    if let Poll::Ready(Ok(read)) = poll {
+      self.position += read;
+    }
+    poll
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn dump_header_describes_entries() {
+    let mut eszip = EszipV2::default();
+    eszip.add_opaque_data("+s/foo".to_string(), Arc::new([1, 2, 3]));
+    eszip.add_import_map(
+      ModuleKind::Json,
+      "file:///import_map.json".to_string(),
+      Arc::from(*b"{}"),
+    );
+    let bytes = eszip.into_bytes();
+
+    let dump = dump_header(bytes.as_slice()).await.unwrap();
+    assert_eq!(dump.version, "ESZIP2.2");
+    assert_eq!(dump.options.checksum, Some(Checksum::NoChecksum));
+    assert_eq!(dump.options.checksum_size, Some(0));
+    // magic + options length + 2 options
+    assert_eq!(dump.modules_header_offset, 8 + 4 + 4);
+    let npm = dump.npm.as_ref().unwrap();
+    assert_eq!(
+      npm.offset,
+      dump.modules_header_offset + 4 + dump.modules_header_len
+    );
+    assert!(npm.packages.is_empty());
+    assert_eq!(dump.sources_offset, npm.offset + 4);
+    // the sources section starts with its length
+    assert_eq!(
+      &bytes[dump.sources_offset..dump.sources_offset + 4],
+      &5u32.to_be_bytes()
+    );
+
+    assert_eq!(
+      serde_json::to_value(&dump.entries).unwrap(),
+      serde_json::json!([
+        {
+          "entryKind": "module",
+          "specifier": "file:///import_map.json",
+          "kind": "json",
+          "sourceOffset": 0,
+          "sourceLen": 2,
+          "sourceMapOffset": 0,
+          "sourceMapLen": 0,
+          "flags": 0,
+        },
+        {
+          "entryKind": "module",
+          "specifier": "+s/foo",
+          "kind": "opaquedata",
+          "sourceOffset": 2,
+          "sourceLen": 3,
+          "sourceMapOffset": 0,
+          "sourceMapLen": 0,
+          "flags": 0,
+        },
+      ])
+    );
+
+    // descriptions can be persisted and read back
+    let json = serde_json::to_value(&dump).unwrap();
+    let read: HeaderDump = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&read).unwrap(), json);
+  }
+
+  #[cfg(feature = "sha256")]
+  #[tokio::test]
+  async fn dump_header_npm_section() {
+    let bytes = std::fs::read("./src/testdata/npm_packages.eszip2_1").unwrap();
+    let dump = dump_header(bytes.as_slice()).await.unwrap();
+    assert_eq!(dump.version, "ESZIP2.1");
+    assert_eq!(dump.options.checksum, Some(Checksum::Sha256));
+    assert_eq!(dump.modules_header_offset, 8);
+    let npm = dump.npm.unwrap();
+    let package = npm
+      .packages
+      .iter()
+      .find(|package| package.id == "package@1.2.2")
+      .unwrap();
+    let a = &npm.packages[package.dependencies["a"] as usize];
+    assert_eq!(a.id, "a@2.2.3");
+    let root = dump
+      .entries
+      .iter()
+      .find_map(|entry| match entry {
+        EntryDump::NpmSpecifier {
+          specifier,
+          package_index,
+        } if specifier == "d@5" => Some(*package_index),
+        _ => None,
+      })
+      .unwrap();
+    assert_eq!(npm.packages[root as usize].id, "d@5.0.0");
+  }
+}
@@ -1,29 +1,65 @@
 // Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
 
+mod binary;
+pub mod bundle;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod diff;
 mod error;
+pub mod extract;
+pub mod inspect;
+pub mod loader;
+pub mod lockfile;
+pub mod metrics;
+pub mod npm;
+#[cfg(feature = "signature")]
+pub mod signature;
+pub mod source_map;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 pub mod v1;
 pub mod v2;
+pub mod validate;
 
+use std::future::Future;
+use std::str::Utf8Error;
 use std::sync::Arc;
 
 use deno_npm::resolution::ValidSerializedNpmResolutionSnapshot;
 use futures::future::BoxFuture;
 use futures::future::LocalBoxFuture;
-use futures::io::AsyncBufReadExt;
 use futures::io::AsyncReadExt;
+use futures::FutureExt;
 use serde::Deserialize;
 use serde::Serialize;
 use v2::EszipV2Modules;
 
+pub use crate::binary::extract_from_binary;
+pub use crate::diff::diff;
+pub use crate::error::BundleError;
+pub use crate::error::FromGraphError;
+pub use crate::error::ImportMapError;
+pub use crate::error::MergeError;
 pub use crate::error::ParseError;
+pub use crate::error::RedirectError;
+pub use crate::error::SourceError;
 pub use crate::v1::EszipV1;
+pub use crate::v2::BuildEszipOptions;
+pub use crate::v2::EmitCache;
 pub use crate::v2::EszipRelativeFileBaseUrl;
 pub use crate::v2::EszipV2;
+pub use crate::v2::EszipV2Builder;
+pub use crate::v2::FromGraphConfig;
 pub use crate::v2::FromGraphOptions;
+pub use crate::v2::MemoryEmitCache;
+pub use crate::v2::ModuleKindResolver;
 
 pub use deno_ast;
 pub use deno_graph;
 
+// Boxing the variants would be a breaking change, and there is usually a single
+// `Eszip` alive at a time.
+#[allow(clippy::large_enum_variant)]
 pub enum Eszip {
   V1(EszipV1),
   V2(EszipV2),
@@ -41,47 +77,61 @@ impl Eszip {
   /// is fully received. This does not mean that the entire file is fully
   /// received or parsed yet. To finish parsing, the future returned by this
   /// function in the second tuple slot needs to be polled.
-  pub async fn parse<R: futures::io::AsyncRead + Unpin + Send + 'static>(
+  ///
+  /// The reader does not need to be buffered, it is wrapped in a
+  /// [`futures::io::BufReader`], which the returned future resolves to. The
+  /// returned future implements `Send` if the reader does, so `!Send` readers
+  /// can be parsed too.
+  pub async fn parse<R: futures::io::AsyncRead + Unpin>(
     reader: R,
-  ) -> Result<(Eszip, EszipParserFuture<R>), ParseError> {
+  ) -> Result<(Eszip, impl Future<Output = EszipParserOutput<R>>), ParseError>
+  {
     let mut reader = futures::io::BufReader::new(reader);
     let mut magic = [0; 8];
     reader.read_exact(&mut magic).await?;
     if EszipV2::has_magic(&magic) {
-      let (eszip, fut) = EszipV2::parse_with_magic(&magic, reader).await?;
-      Ok((Eszip::V2(eszip), Box::pin(fut)))
+      let (eszip, fut) =
+        EszipV2::parse_with_magic(&magic, reader, Default::default()).await?;
+      Ok((Eszip::V2(eszip), fut.left_future()))
     } else {
       let mut buffer = Vec::new();
       let mut reader_w_magic = magic.chain(&mut reader);
       reader_w_magic.read_to_end(&mut buffer).await?;
       let eszip = EszipV1::parse(&buffer)?;
       let fut = async move { Ok::<_, ParseError>(reader) };
-      Ok((Eszip::V1(eszip), Box::pin(fut)))
+      Ok((Eszip::V1(eszip), fut.right_future()))
     }
   }
 
-  /// Parse a byte stream into an Eszip. This function completes when the header
-  /// is fully received. This does not mean that the entire file is fully
-  /// received or parsed yet. To finish parsing, the future returned by this
-  /// function in the second tuple slot needs to be polled.
+  /// Like [`Eszip::parse`], but the returned future is boxed.
   ///
-  /// As opposed to [`Eszip::parse`], this method accepts `!Send` reader. The
-  /// returned future does not implement `Send` either.
+  /// [`Eszip::parse`] accepts `!Send` readers too, and can be used instead.
   pub async fn parse_local<R: futures::io::AsyncRead + Unpin + 'static>(
     reader: R,
   ) -> Result<(Eszip, EszipParserLocalFuture<R>), ParseError> {
-    let mut reader = futures::io::BufReader::new(reader);
-    reader.fill_buf().await?;
-    let buffer = reader.buffer();
-    if EszipV2::has_magic(buffer) {
-      let (eszip, fut) = EszipV2::parse(reader).await?;
-      Ok((Eszip::V2(eszip), Box::pin(fut)))
-    } else {
-      let mut buffer = Vec::new();
-      reader.read_to_end(&mut buffer).await?;
-      let eszip = EszipV1::parse(&buffer)?;
-      let fut = async move { Ok::<_, ParseError>(reader) };
-      Ok((Eszip::V1(eszip), Box::pin(fut)))
+    let (eszip, fut) = Eszip::parse(reader).await?;
+    Ok((eszip, Box::pin(fut)))
+  }
+
+  /// Parse a byte stream into an [`EszipV2`], converting V1 eszips with
+  /// [`Eszip::upgrade`], so that downstream code only has to handle the V2
+  /// API. Like [`Eszip::parse`], the future returned in the second tuple slot
+  /// needs to be polled to finish parsing.
+  pub async fn parse_upgrading<
+    R: futures::io::AsyncRead + Unpin + Send + 'static,
+  >(
+    reader: R,
+  ) -> Result<(EszipV2, EszipParserFuture<R>), ParseError> {
+    let (eszip, fut) = Eszip::parse(reader).await?;
+    Ok((eszip.upgrade(), Box::pin(fut)))
+  }
+
+  /// Convert this eszip into an [`EszipV2`], see [`EszipV1::into_v2`]. V2
+  /// eszips are returned as is.
+  pub fn upgrade(self) -> EszipV2 {
+    match self {
+      Eszip::V1(eszip) => eszip.into_v2(),
+      Eszip::V2(eszip) => eszip,
     }
   }
 
@@ -112,6 +162,25 @@ impl Eszip {
     }
   }
 
+  /// Get the specifiers of all the modules in the eszip, including
+  /// redirects. V2 eszips list them in the order they are stored in, V1
+  /// eszips in sorted order.
+  pub fn specifiers(&self) -> Vec<String> {
+    match self {
+      Eszip::V1(eszip) => eszip.specifiers(),
+      Eszip::V2(eszip) => eszip.specifiers(),
+    }
+  }
+
+  /// Get an iterator over all the modules (including an import map, if any)
+  /// in this eszip archive, without consuming it.
+  pub fn iter(&self) -> Box<dyn Iterator<Item = (String, Module)> + '_> {
+    match self {
+      Eszip::V1(eszip) => Box::new(eszip.iter()),
+      Eszip::V2(eszip) => Box::new(eszip.iter()),
+    }
+  }
+
   /// Takes the npm snapshot out of the eszip.
   pub fn take_npm_snapshot(
     &mut self,
@@ -163,6 +232,116 @@ impl Module {
     }
   }
 
+  /// Like [`Module::source`], but returns an error instead of `None` when the
+  /// source was read from the archive and does not match its checksum, or
+  /// could not be read. With [`EszipV2::open_seekable`], the checksum of a
+  /// source is only verified the first time it is requested, so the cost of
+  /// opening an archive does not depend on the modules that are not used.
+  pub async fn try_source(&self) -> Result<Option<Arc<[u8]>>, SourceError> {
+    match &self.inner {
+      ModuleInner::V1(eszip_v1) => {
+        Ok(eszip_v1.get_module_source(&self.specifier))
+      }
+      ModuleInner::V2(eszip_v2) => {
+        eszip_v2.try_get_module_source(&self.specifier).await
+      }
+    }
+  }
+
+  /// Get the source code of the module as a string, or an error if it is not
+  /// valid UTF-8, e.g. for opaque data. Returns `Ok(None)` when
+  /// [`Module::source`] returns `None`.
+  pub async fn source_str(&self) -> Result<Option<Arc<str>>, Utf8Error> {
+    self.source().await.map(arc_str_from_utf8).transpose()
+  }
+
+  /// Get the length in bytes of the source code of the module, without
+  /// waiting for it to be loaded. Returns `None` if it is not known, e.g. for
+  /// external modules and for sources that were taken.
+  pub fn source_len(&self) -> Option<usize> {
+    match &self.inner {
+      ModuleInner::V1(eszip_v1) => {
+        eszip_v1.get_module_source_len(&self.specifier)
+      }
+      ModuleInner::V2(eszip_v2) => {
+        eszip_v2.get_module_source_len(&self.specifier)
+      }
+    }
+  }
+
+  /// Get the length in bytes of the source map of the module, without
+  /// waiting for it to be loaded. Returns `None` if it is not known, and for
+  /// V1 eszips, which do not store source maps.
+  pub fn source_map_len(&self) -> Option<usize> {
+    match &self.inner {
+      ModuleInner::V1(_) => None,
+      ModuleInner::V2(eszip) => {
+        eszip.get_module_source_map_len(&self.specifier)
+      }
+    }
+  }
+
+  /// Check whether the module has a source, which may be empty. Modules
+  /// added with [`EszipV2::add_placeholder`] have none, and
+  /// [`Module::source`] returns `None` for them.
+  pub fn has_source(&self) -> bool {
+    match &self.inner {
+      ModuleInner::V1(_) => true,
+      ModuleInner::V2(eszip) => eszip.module_has_source(&self.specifier),
+    }
+  }
+
+  /// Get the flags of the module. V1 eszips do not store flags, so they are
+  /// always empty for them. See [`EszipV2::set_module_flags`].
+  pub fn flags(&self) -> ModuleFlags {
+    match &self.inner {
+      ModuleInner::V1(_) => ModuleFlags::empty(),
+      ModuleInner::V2(eszip) => eszip.get_module_flags(&self.specifier),
+    }
+  }
+
+  /// The type of the import attribute the module must be imported with, e.g.
+  /// `"bytes"` for `import data from "./data.bin" with { type: "bytes" }`, or
+  /// `None` if it is imported without one.
+  pub fn import_attribute_type(&self) -> Option<&'static str> {
+    match self.kind {
+      ModuleKind::Json | ModuleKind::Jsonc => Some("json"),
+      ModuleKind::Bytes => Some("bytes"),
+      ModuleKind::Text => Some("text"),
+      ModuleKind::JavaScript
+      | ModuleKind::OpaqueData
+      | ModuleKind::Wasm
+      | ModuleKind::Declaration => None,
+    }
+  }
+
+  /// Get the source code of the module as it was loaded, before it was
+  /// transpiled. V1 eszips always keep it. V2 eszips only keep it for the
+  /// transpiled modules of eszips built with
+  /// [`FromGraphConfig::keep_original_sources`], so `None` is returned for
+  /// the other modules.
+  pub async fn original_source(&self) -> Option<Arc<[u8]>> {
+    match &self.inner {
+      ModuleInner::V1(eszip_v1) => {
+        eszip_v1.get_module_original_source(&self.specifier)
+      }
+      ModuleInner::V2(eszip) => {
+        eszip.get_module_original_source(&self.specifier).await
+      }
+    }
+  }
+
+  /// Get the content type the module was served with, as recorded in V1
+  /// eszips. Returns `None` for V2 eszips and for modules without one.
+  pub fn content_type(&self) -> Option<String> {
+    match &self.inner {
+      ModuleInner::V1(eszip_v1) => {
+        eszip_v1.get_module_content_type(&self.specifier)
+      }
+      ModuleInner::V2(_) => None,
+    }
+  }
+
   /// Take source code of the module. This will remove the source code from memory and
   /// the subsequent calls to `take_source()` will return `None`.
   /// For V1, this will take the entire module and returns the source code. We don't need
@@ -186,6 +365,45 @@ impl Module {
     }
   }
 
+  /// Get the source map of the module as a string, or an error if it is not
+  /// valid UTF-8. Returns `Ok(None)` when [`Module::source_map`] returns
+  /// `None`.
+  pub async fn source_map_str(&self) -> Result<Option<Arc<str>>, Utf8Error> {
+    self.source_map().await.map(arc_str_from_utf8).transpose()
+  }
+
+  /// Get the SHA-256 checksum of the content of a remote module as it was
+  /// loaded when the eszip was built, in the hex format used by `deno.lock`.
+  /// Returns `None` for local modules and for modules of eszips that do not
+  /// record it, see [`FromGraphConfig::record_integrity`].
+  pub fn integrity(&self) -> Option<String> {
+    match &self.inner {
+      ModuleInner::V1(_) => None,
+      ModuleInner::V2(eszip) => eszip.get_module_integrity(&self.specifier),
+    }
+  }
+
+  /// Get the modules this module imports, as the specifiers they are stored
+  /// under in the archive, in the order in which they are imported. Returns
+  /// `None` for modules of eszips that do not record them, see
+  /// [`FromGraphConfig::record_dependencies`].
+  pub fn dependencies(&self) -> Option<Vec<ModuleDependency>> {
+    match &self.inner {
+      ModuleInner::V1(_) => None,
+      ModuleInner::V2(eszip) => eszip.get_module_dependencies(&self.specifier),
+    }
+  }
+
+  /// Get the url the source of the module is fetched from, if it is an
+  /// external module that is not stored in the archive. See
+  /// [`EszipV2::add_external_module`].
+  pub fn external_url(&self) -> Option<v2::Url> {
+    match &self.inner {
+      ModuleInner::V1(_) => None,
+      ModuleInner::V2(eszip) => eszip.get_module_external_url(&self.specifier),
+    }
+  }
+
   /// Take source map of the module. This will remove the source map from memory and
   /// the subsequent calls to `take_source_map()` will return `None`.
   pub async fn take_source_map(&self) -> Option<Arc<[u8]>> {
@@ -198,20 +416,125 @@ impl Module {
   }
 }
 
+fn arc_str_from_utf8(bytes: Arc<[u8]>) -> Result<Arc<str>, Utf8Error> {
+  std::str::from_utf8(&bytes).map(Arc::from)
+}
+
 /// This is the kind of module that is being stored. This is the same enum as is
 /// present in [deno_core::ModuleType] except that this has additional variant
 /// `Jsonc` which is used when an import map is embedded in Deno's config file
 /// that can be JSONC.
 /// Note that a module of type `Jsonc` can be used only as an import map, not as
 /// a normal module.
+///
+/// More kinds may be added in later versions, which is why matches on it need a
+/// wildcard arm. The kinds from `Wasm` on were added in version 0.81.0 of this
+/// crate, without a new format version: earlier versions fail to parse eszips
+/// with modules of these kinds, with [`ParseError::InvalidV2ModuleKind`].
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum ModuleKind {
   JavaScript = 0,
   Json = 1,
   Jsonc = 2,
   OpaqueData = 3,
+  /// A WebAssembly module, stored as its binary. Since version 0.81.0.
+  Wasm = 4,
+  /// A TypeScript declaration file, stored as its source for runtimes that
+  /// type check against the archive. It can not be evaluated. See
+  /// [`FromGraphConfig::include_declarations`]. Since version 0.81.0.
+  Declaration = 5,
+  /// A module imported with a `bytes` import attribute, e.g.
+  /// `import data from "./data.bin" with { type: "bytes" }`, stored as it was
+  /// loaded. See [`EszipV2::build`]. Since version 0.81.0.
+  Bytes = 6,
+  /// A module imported with a `text` import attribute, stored as it was
+  /// loaded. Its source is valid UTF-8. Since version 0.81.0.
+  Text = 7,
+}
+
+/// The format of a configuration file embedded in an archive, see
+/// [`EszipV2::add_config`].
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigKind {
+  /// A `deno.json`.
+  Json = 0,
+  /// A `deno.jsonc`.
+  Jsonc = 1,
+  Toml = 2,
+}
+
+/// A module imported by another module, see [`Module::dependencies`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleDependency {
+  /// The specifier the imported module is stored under in the archive. It
+  /// may be a redirect, or an `npm:` specifier.
+  pub specifier: String,
+  /// Whether the module is imported with a dynamic `import()`, and so is only
+  /// loaded if the import is evaluated.
+  pub is_dynamic: bool,
+}
+
+/// Flags of a module, stored in a byte of its entry in the modules header.
+/// Archives with flags are written as version 2.3 of the format, as readers of
+/// version 2.2 would misread the extra byte; new flags can be added without
+/// another revision. Bits without a constant are reserved; they are kept as is
+/// when an archive is read and written again. They serialize as the bits of
+/// the byte.
+#[derive(
+  Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct ModuleFlags(u8);
+
+impl ModuleFlags {
+  /// The module may be missing at runtime without being an error.
+  pub const OPTIONAL: Self = Self(1 << 0);
+  /// The source of the module is not stored in the archive, see
+  /// [`EszipV2::add_external_module`]. Set by this crate.
+  pub const EXTERNAL: Self = Self(1 << 1);
+  /// The source of the module is compressed.
+  pub const COMPRESSED: Self = Self(1 << 2);
+
+  pub const fn empty() -> Self {
+    Self(0)
+  }
+
+  pub const fn from_bits(bits: u8) -> Self {
+    Self(bits)
+  }
+
+  pub const fn bits(self) -> u8 {
+    self.0
+  }
+
+  pub const fn is_empty(self) -> bool {
+    self.0 == 0
+  }
+
+  /// Check whether all the flags of `other` are set.
+  pub const fn contains(self, other: Self) -> bool {
+    self.0 & other.0 == other.0
+  }
+}
+
+impl std::ops::BitOr for ModuleFlags {
+  type Output = Self;
+
+  fn bitor(self, rhs: Self) -> Self {
+    Self(self.0 | rhs.0)
+  }
+}
+
+impl std::ops::BitOrAssign for ModuleFlags {
+  fn bitor_assign(&mut self, rhs: Self) {
+    self.0 |= rhs.0;
+  }
 }
 
 #[cfg(test)]
@@ -222,6 +545,44 @@ mod tests {
   use futures::StreamExt;
   use futures::TryStreamExt;
 
+  #[tokio::test]
+  async fn parse_non_send_reader() {
+    // a reader that can not be sent to another thread
+    struct LocalReader(
+      futures::io::Cursor<Vec<u8>>,
+      std::marker::PhantomData<std::rc::Rc<()>>,
+    );
+
+    impl futures::io::AsyncRead for LocalReader {
+      fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+      ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+      }
+    }
+
+    let mut eszip = EszipV2::default();
+    eszip.add_opaque_data("file:///a".to_string(), Arc::new([1, 2, 3]));
+    let reader = LocalReader(
+      futures::io::Cursor::new(eszip.into_bytes()),
+      std::marker::PhantomData,
+    );
+    let (eszip, fut) = Eszip::parse(reader).await.unwrap();
+    fut.await.unwrap();
+    let module = eszip.get_module("file:///a").unwrap();
+    assert_eq!(&*module.source().await.unwrap(), &[1, 2, 3]);
+  }
+
+  #[test]
+  fn module_flags_serde() {
+    let flags = ModuleFlags::OPTIONAL | ModuleFlags::EXTERNAL;
+    let json = serde_json::to_value(flags).unwrap();
+    assert_eq!(json, serde_json::json!(3));
+    assert_eq!(serde_json::from_value::<ModuleFlags>(json).unwrap(), flags);
+  }
+
   #[tokio::test]
   async fn parse_v1() {
     let file = std::fs::File::open("./src/testdata/basic.json").unwrap();
@@ -241,6 +602,69 @@ mod tests {
     eszip.get_module("file:///main.ts").unwrap();
   }
 
+  #[tokio::test]
+  async fn iter_v1_and_v2() {
+    let specifier = "https://gist.githubusercontent.com/lucacasonato/f3e21405322259ca4ed155722390fda2/raw/e25acb49b681e8e1da5a2a33744b7a36d538712d/hello.js";
+    let file = std::fs::File::open("./src/testdata/basic.json").unwrap();
+    let (eszip, fut) = Eszip::parse(AllowStdIo::new(file)).await.unwrap();
+    fut.await.unwrap();
+    assert_eq!(eszip.specifiers(), vec![specifier]);
+    let specifiers = eszip.iter().map(|(s, _)| s).collect::<Vec<_>>();
+    assert_eq!(specifiers, vec![specifier]);
+
+    let mut eszip_v2 = EszipV2::default();
+    eszip_v2.add_to_front(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"console.log(1);",
+      [],
+    );
+    let eszip = Eszip::V2(eszip_v2);
+    assert_eq!(eszip.specifiers(), vec!["file:///main.js"]);
+    let (specifier, module) = eszip.iter().next().unwrap();
+    assert_eq!(specifier, "file:///main.js");
+    assert_eq!(&*module.source().await.unwrap(), b"console.log(1);");
+  }
+
+  #[tokio::test]
+  async fn source_str() {
+    let mut eszip = EszipV2::default();
+    eszip.add_to_front(
+      ModuleKind::JavaScript,
+      "file:///main.js".to_string(),
+      *b"console.log(1);",
+      *b"{}",
+    );
+    eszip.add_opaque_data("file:///data".to_string(), Arc::new([0xff, 0xfe]));
+    eszip.add_placeholder(ModuleKind::JavaScript, "ext:runtime.js".to_string());
+    let eszip = Eszip::V2(eszip);
+
+    let main = eszip.get_module("file:///main.js").unwrap();
+    assert_eq!(
+      &*main.source_str().await.unwrap().unwrap(),
+      "console.log(1);"
+    );
+    assert_eq!(&*main.source_map_str().await.unwrap().unwrap(), "{}");
+    let data = eszip.get_module("file:///data").unwrap();
+    assert!(data.source_str().await.is_err());
+    let placeholder = eszip.get_module("ext:runtime.js").unwrap();
+    assert!(placeholder.source_str().await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn parse_upgrading_v1() {
+    let file = std::fs::File::open("./src/testdata/dotland.json").unwrap();
+    let (eszip, fut) =
+      Eszip::parse_upgrading(AllowStdIo::new(file)).await.unwrap();
+    fut.await.unwrap();
+    assert_eq!(eszip.specifiers().len(), 37);
+    let module = eszip.get_module("file:///src/worker/handler.ts").unwrap();
+    assert_eq!(module.kind, ModuleKind::JavaScript);
+    let source = module.source().await.unwrap();
+    let source = std::str::from_utf8(&source).unwrap();
+    assert!(!source.contains("import type { ConnInfo }"));
+  }
+
   #[tokio::test]
   async fn take_source_v1() {
     let file = std::fs::File::open("./src/testdata/basic.json").unwrap();
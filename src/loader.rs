@@ -0,0 +1,216 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! A [`Loader`] for building module graphs that dispatches on the scheme of
+//! the specifier.
+
+use std::sync::Arc;
+
+use deno_graph::source::load_data_url;
+use deno_graph::source::LoadFuture;
+use deno_graph::source::LoadOptions;
+use deno_graph::source::LoadResponse;
+use deno_graph::source::Loader;
+use deno_graph::ModuleSpecifier;
+use url::Url;
+
+/// A [`Loader`] that loads `file:` specifiers from the file system and
+/// `data:` specifiers from their url. `http:` and `https:` specifiers are
+/// delegated to the remote loader, if one is configured. Other schemes fail
+/// to load.
+///
+/// When a checksum is provided in the [`LoadOptions`], e.g. from the locker of
+/// the module graph, the loaded content is verified against it.
+///
+/// eszip does not depend on an HTTP client, so remote modules are only
+/// supported when a loader for them is provided with
+/// [`CompositeLoader::with_remote`].
+#[derive(Default)]
+pub struct CompositeLoader {
+  remote: Option<Box<dyn Loader>>,
+}
+
+impl CompositeLoader {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Use the given loader to load `http:` and `https:` specifiers.
+  pub fn with_remote(mut self, remote: impl Loader + 'static) -> Self {
+    self.remote = Some(Box::new(remote));
+    self
+  }
+}
+
+impl Loader for CompositeLoader {
+  fn load(
+    &self,
+    specifier: &ModuleSpecifier,
+    options: LoadOptions,
+  ) -> LoadFuture {
+    let maybe_checksum = options.maybe_checksum.clone();
+    let future: LoadFuture = match specifier.scheme() {
+      "http" | "https" => match &self.remote {
+        Some(remote) => remote.load(specifier, options),
+        None => {
+          let err = anyhow::anyhow!(
+            "remote modules are not supported without a remote loader: {}",
+            specifier
+          );
+          Box::pin(async move { Err(err) })
+        }
+      },
+      "data" => {
+        let result = load_data_url(specifier);
+        Box::pin(async move { result })
+      }
+      "file" => {
+        let result = load_file(specifier);
+        Box::pin(async move { result })
+      }
+      scheme => {
+        let err = anyhow::anyhow!("unsupported scheme: {}", scheme);
+        Box::pin(async move { Err(err) })
+      }
+    };
+
+    match maybe_checksum {
+      Some(checksum) => Box::pin(async move {
+        let response = future.await?;
+        if let Some(LoadResponse::Module { content, .. }) = &response {
+          checksum.check_source(content)?;
+        }
+        Ok(response)
+      }),
+      None => future,
+    }
+  }
+}
+
+fn load_file(
+  specifier: &ModuleSpecifier,
+) -> Result<Option<LoadResponse>, anyhow::Error> {
+  let path = specifier
+    .to_file_path()
+    .map_err(|_| anyhow::anyhow!("invalid file specifier: {}", specifier))?;
+  let path = match std::fs::canonicalize(path) {
+    Ok(path) => path,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err.into()),
+  };
+  let content = std::fs::read(&path)?;
+  Ok(Some(LoadResponse::Module {
+    specifier: Url::from_file_path(&path).unwrap(),
+    maybe_headers: None,
+    content: Arc::from(content),
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use deno_graph::source::CacheSetting;
+  use deno_graph::source::ChecksumIntegrityError;
+  use deno_graph::source::LoaderChecksum;
+  use deno_graph::source::MemoryLoader;
+  use deno_graph::source::Source;
+
+  use super::*;
+
+  fn options() -> LoadOptions {
+    LoadOptions {
+      is_dynamic: false,
+      was_dynamic_root: false,
+      cache_setting: CacheSetting::Use,
+      maybe_checksum: None,
+    }
+  }
+
+  fn content(response: LoadResponse) -> String {
+    match response {
+      LoadResponse::Module { content, .. } => {
+        String::from_utf8(content.to_vec()).unwrap()
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  #[tokio::test]
+  async fn dispatches_on_scheme() {
+    let loader = CompositeLoader::new().with_remote(MemoryLoader::new(
+      vec![(
+        "https://example.com/mod.ts",
+        Source::Module {
+          specifier: "https://example.com/mod.ts",
+          maybe_headers: None,
+          content: "export const remote = true;",
+        },
+      )],
+      vec![],
+    ));
+
+    let path = std::fs::canonicalize("./src/testdata/source/b.ts").unwrap();
+    let specifier = Url::from_file_path(&path).unwrap();
+    let response = loader.load(&specifier, options()).await.unwrap().unwrap();
+    assert_eq!(content(response), std::fs::read_to_string(&path).unwrap());
+
+    let specifier = Url::from_file_path(path.with_file_name("missing.ts"));
+    let response = loader.load(&specifier.unwrap(), options()).await.unwrap();
+    assert!(response.is_none());
+
+    let specifier = Url::parse("data:text/plain,hello").unwrap();
+    let response = loader.load(&specifier, options()).await.unwrap().unwrap();
+    assert_eq!(content(response), "hello");
+
+    let specifier = Url::parse("https://example.com/mod.ts").unwrap();
+    let response = loader.load(&specifier, options()).await.unwrap().unwrap();
+    assert_eq!(content(response), "export const remote = true;");
+
+    let specifier = Url::parse("ftp://example.com/mod.ts").unwrap();
+    let err = loader.load(&specifier, options()).await.unwrap_err();
+    assert_eq!(err.to_string(), "unsupported scheme: ftp");
+  }
+
+  #[tokio::test]
+  async fn verifies_checksum() {
+    let specifier = Url::parse("data:text/plain,hello").unwrap();
+    let loader = CompositeLoader::new();
+
+    let response = loader
+      .load(
+        &specifier,
+        LoadOptions {
+          maybe_checksum: Some(LoaderChecksum::new(LoaderChecksum::gen(
+            b"hello",
+          ))),
+          ..options()
+        },
+      )
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(content(response), "hello");
+
+    let err = loader
+      .load(
+        &specifier,
+        LoadOptions {
+          maybe_checksum: Some(LoaderChecksum::new("0".repeat(64))),
+          ..options()
+        },
+      )
+      .await
+      .unwrap_err();
+    assert!(err.downcast_ref::<ChecksumIntegrityError>().is_some());
+  }
+
+  #[tokio::test]
+  async fn remote_requires_loader() {
+    let specifier = Url::parse("https://example.com/mod.ts").unwrap();
+    let err = CompositeLoader::new()
+      .load(&specifier, options())
+      .await
+      .unwrap_err();
+    assert!(err
+      .to_string()
+      .starts_with("remote modules are not supported"));
+  }
+}
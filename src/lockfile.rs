@@ -0,0 +1,187 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Verifying the contents of remote modules against a `deno.lock` while
+//! building an eszip, and recreating the npm section of a `deno.lock` from an
+//! eszip.
+
+use std::path::PathBuf;
+
+use deno_graph::source::LoaderChecksum;
+use deno_graph::source::Locker;
+use deno_graph::ModuleSpecifier;
+use deno_lockfile::Lockfile;
+use deno_lockfile::NpmPackageDependencyLockfileInfo;
+use deno_lockfile::NpmPackageLockfileInfo;
+use deno_npm::resolution::ValidSerializedNpmResolutionSnapshot;
+use deno_semver::jsr::JsrDepPackageReq;
+use deno_semver::package::PackageNv;
+
+/// A [`Locker`] backed by a parsed `deno.lock`, to pass to
+/// [`crate::BuildEszipOptions::locker`].
+///
+/// While the module graph is built, the checksums of remote modules and JSR
+/// package manifests recorded in the lockfile are handed to the loader, which
+/// fails the build if the loaded content does not match. Checksums of content
+/// that is not in the lockfile yet are added to it.
+pub struct LockfileLocker<'a>(pub &'a mut Lockfile);
+
+impl Locker for LockfileLocker<'_> {
+  fn get_remote_checksum(
+    &self,
+    specifier: &ModuleSpecifier,
+  ) -> Option<LoaderChecksum> {
+    self
+      .0
+      .remote()
+      .get(specifier.as_str())
+      .map(|checksum| LoaderChecksum::new(checksum.clone()))
+  }
+
+  fn has_remote_checksum(&self, specifier: &ModuleSpecifier) -> bool {
+    self.0.remote().contains_key(specifier.as_str())
+  }
+
+  fn set_remote_checksum(
+    &mut self,
+    specifier: &ModuleSpecifier,
+    checksum: LoaderChecksum,
+  ) {
+    self
+      .0
+      .insert_remote(specifier.to_string(), checksum.into_string());
+  }
+
+  fn get_pkg_manifest_checksum(
+    &self,
+    package_nv: &PackageNv,
+  ) -> Option<LoaderChecksum> {
+    self
+      .0
+      .content
+      .packages
+      .jsr
+      .get(package_nv)
+      .map(|package| LoaderChecksum::new(package.integrity.clone()))
+  }
+
+  fn set_pkg_manifest_checksum(
+    &mut self,
+    package_nv: &PackageNv,
+    checksum: LoaderChecksum,
+  ) {
+    self
+      .0
+      .insert_package(package_nv.clone(), checksum.into_string());
+  }
+}
+
+/// Create a lockfile at `path` with the npm packages of a resolution snapshot,
+/// e.g. the one of an eszip (see [`crate::EszipV2::take_npm_snapshot`]), so
+/// that the packages can be installed again.
+///
+/// Eszips do not store the integrity of the npm packages, so the packages of
+/// a snapshot read from an eszip have an empty integrity.
+pub fn npm_snapshot_lockfile(
+  path: PathBuf,
+  snapshot: &ValidSerializedNpmResolutionSnapshot,
+) -> Lockfile {
+  let snapshot = snapshot.as_serialized();
+  let mut lockfile = Lockfile::new_empty(path, false);
+  for (req, id) in &snapshot.root_packages {
+    lockfile.insert_package_specifier(
+      JsrDepPackageReq::npm(req.clone()),
+      // the name of the package is the one of the requirement
+      format!("{}{}", id.nv.version, id.peer_deps_serialized()),
+    );
+  }
+  for package in &snapshot.packages {
+    lockfile.insert_npm_package(NpmPackageLockfileInfo {
+      serialized_id: package.id.as_serialized(),
+      integrity: package.dist.integrity().for_lockfile(),
+      dependencies: package
+        .dependencies
+        .iter()
+        .map(|(name, id)| NpmPackageDependencyLockfileInfo {
+          name: name.clone(),
+          id: id.as_serialized(),
+        })
+        .collect(),
+    });
+  }
+  lockfile
+}
+
+#[cfg(test)]
+mod tests {
+  use deno_graph::source::MemoryLoader;
+  use deno_graph::source::Source;
+  use deno_lockfile::NewLockfileOptions;
+
+  use super::*;
+  use crate::loader::CompositeLoader;
+  use crate::BuildEszipOptions;
+  use crate::EszipV2;
+
+  const MAIN: &str = "https://example.com/main.ts";
+
+  fn loader() -> CompositeLoader {
+    CompositeLoader::new().with_remote(MemoryLoader::new(
+      vec![(
+        MAIN,
+        Source::Module {
+          specifier: MAIN,
+          maybe_headers: None,
+          content: "console.log(1);",
+        },
+      )],
+      vec![],
+    ))
+  }
+
+  fn new_lockfile(checksum: &str) -> Lockfile {
+    Lockfile::new(NewLockfileOptions {
+      file_path: "/deno.lock".into(),
+      content: &format!(
+        r#"{{ "version": "4", "remote": {{ "{MAIN}": "{checksum}" }} }}"#
+      ),
+      overwrite: false,
+    })
+    .unwrap()
+  }
+
+  async fn build(lockfile: &mut Lockfile) -> Result<EszipV2, anyhow::Error> {
+    let mut locker = LockfileLocker(lockfile);
+    EszipV2::build(
+      vec![ModuleSpecifier::parse(MAIN).unwrap()],
+      &loader(),
+      BuildEszipOptions {
+        locker: Some(&mut locker),
+        ..Default::default()
+      },
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn build_verifies_remote_checksums() {
+    let mut lockfile = new_lockfile(&LoaderChecksum::gen(b"console.log(1);"));
+    let eszip = build(&mut lockfile).await.unwrap();
+    assert_eq!(eszip.specifiers(), vec![MAIN]);
+
+    let mut lockfile = new_lockfile(&"0".repeat(64));
+    let err = build(&mut lockfile).await.unwrap_err();
+    let err = err.to_string();
+    assert!(err.contains("Integrity check failed"), "{err}");
+    assert!(err.contains(MAIN), "{err}");
+  }
+
+  #[tokio::test]
+  async fn build_records_missing_checksums() {
+    let mut lockfile = Lockfile::new_empty("/deno.lock".into(), false);
+    build(&mut lockfile).await.unwrap();
+    assert_eq!(
+      lockfile.remote().get(MAIN).unwrap(),
+      &LoaderChecksum::gen(b"console.log(1);")
+    );
+  }
+}
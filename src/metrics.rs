@@ -0,0 +1,234 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Hooks for collecting metrics while eszips are parsed and built.
+
+use std::time::Duration;
+
+use deno_graph::ModuleSpecifier;
+use futures::channel::mpsc::UnboundedSender;
+
+/// Observer notified of the work done while parsing an eszip with
+/// [`crate::v2::ParseOptions::observer`], building one with
+/// [`crate::FromGraphConfig::observer`] or serializing one with
+/// [`crate::EszipV2::into_bytes_with_observer`], e.g. to export it as metrics
+/// or to report progress.
+///
+/// All methods do nothing by default, so implementors only need to override
+/// the ones they are interested in. The methods are called synchronously from
+/// the parser and builder, so they should return quickly.
+pub trait EszipObserver: Send + Sync {
+  /// Called with the size of each section read from an archive, including
+  /// its checksum.
+  fn bytes_read(&self, _bytes: usize) {}
+
+  /// Called once the header of an archive has been parsed, with the number of
+  /// modules and redirects in it and the length of the sources and source
+  /// maps that are left to read, e.g. to show the progress of reading them
+  /// with [`Self::bytes_read`].
+  fn header_parsed(&self, _entries: usize, _data_len: u64) {}
+
+  /// Called once the source of a module has been read from an archive and
+  /// is available from [`crate::Module::source`], e.g. to start evaluating the
+  /// entry module while the rest of the archive is still being read.
+  fn module_parsed(&self, _specifier: &str) {}
+
+  /// Called once all the sources and source maps of an archive have been
+  /// read. It is not called if reading them fails or is cancelled.
+  fn parse_completed(&self) {}
+
+  /// Called after the checksum of a section of `bytes` bytes has been
+  /// verified, with the time that took.
+  fn checksum_verified(&self, _bytes: usize, _duration: Duration) {}
+
+  /// Called after a module has been transpiled while building an eszip, with
+  /// the time that took.
+  fn module_transpiled(
+    &self,
+    _specifier: &ModuleSpecifier,
+    _duration: Duration,
+  ) {
+  }
+
+  /// Called for each module written by
+  /// [`crate::EszipV2::into_bytes_with_observer`], with the length of its
+  /// source and source map. Each module is reported once, even if the archive
+  /// needs to be written again with 64-bit offsets.
+  fn module_serialized(&self, _specifier: &str, _bytes: usize) {}
+}
+
+/// Sends the specifier of each module whose source has been read from an
+/// archive, see [`EszipObserver::module_parsed`].
+impl EszipObserver for UnboundedSender<String> {
+  fn module_parsed(&self, specifier: &str) {
+    // the receiver may have been dropped
+    let _ = self.unbounded_send(specifier.to_string());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+  use std::sync::Arc;
+  use std::sync::Mutex;
+
+  use deno_graph::source::MemoryLoader;
+  use deno_graph::source::Source;
+  use futures::FutureExt;
+  use futures::StreamExt;
+
+  use super::*;
+  use crate::v2::ParseOptions;
+  use crate::BuildEszipOptions;
+  use crate::EszipV2;
+  use crate::FromGraphConfig;
+
+  #[derive(Default)]
+  struct Counts {
+    bytes_read: AtomicUsize,
+    checksums: AtomicUsize,
+    header: Mutex<Option<(usize, u64)>>,
+    parsed: Mutex<Vec<String>>,
+    completed: AtomicUsize,
+    transpiled: Mutex<Vec<String>>,
+    serialized: Mutex<Vec<(String, usize)>>,
+  }
+
+  impl EszipObserver for Counts {
+    fn bytes_read(&self, bytes: usize) {
+      self.bytes_read.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn header_parsed(&self, entries: usize, data_len: u64) {
+      *self.header.lock().unwrap() = Some((entries, data_len));
+    }
+
+    fn module_parsed(&self, specifier: &str) {
+      self.parsed.lock().unwrap().push(specifier.to_string());
+    }
+
+    fn parse_completed(&self) {
+      self.completed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn checksum_verified(&self, _bytes: usize, _duration: Duration) {
+      self.checksums.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn module_transpiled(
+      &self,
+      specifier: &ModuleSpecifier,
+      _duration: Duration,
+    ) {
+      self.transpiled.lock().unwrap().push(specifier.to_string());
+    }
+
+    fn module_serialized(&self, specifier: &str, bytes: usize) {
+      let serialized = (specifier.to_string(), bytes);
+      self.serialized.lock().unwrap().push(serialized);
+    }
+  }
+
+  #[tokio::test]
+  async fn notifies_loaded_modules() {
+    let mut eszip = EszipV2::default();
+    eszip.add_opaque_data("file:///b".to_string(), Arc::new([2]));
+    eszip.add_opaque_data("file:///a".to_string(), Arc::new([1]));
+    let bytes = eszip.into_bytes();
+
+    let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+    let (eszip, fut) = EszipV2::parse_with_options(
+      futures::io::BufReader::new(futures::io::Cursor::new(bytes)),
+      ParseOptions {
+        observer: Some(Arc::new(sender)),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    let loading = tokio::spawn(fut);
+
+    for expected in ["file:///b", "file:///a"] {
+      let specifier = receiver.next().await.unwrap();
+      assert_eq!(specifier, expected);
+      // the source is ready once the module is announced
+      let module = eszip.get_module(&specifier).unwrap();
+      assert!(module.source().now_or_never().unwrap().is_some());
+    }
+    loading.await.unwrap().unwrap();
+    assert!(receiver.next().await.is_none());
+  }
+
+  #[tokio::test]
+  async fn observes_build_and_parse() {
+    let loader = MemoryLoader::new(
+      vec![
+        (
+          "file:///main.ts",
+          Source::Module {
+            specifier: "file:///main.ts",
+            maybe_headers: None,
+            content: "import './a.js';\nconst a: number = 1;",
+          },
+        ),
+        (
+          "file:///a.js",
+          Source::Module {
+            specifier: "file:///a.js",
+            maybe_headers: None,
+            content: "console.log(1);",
+          },
+        ),
+      ],
+      vec![],
+    );
+    let counts = Arc::new(Counts::default());
+    let eszip = EszipV2::build(
+      vec![ModuleSpecifier::parse("file:///main.ts").unwrap()],
+      &loader,
+      BuildEszipOptions {
+        config: FromGraphConfig {
+          observer: Some(&*counts),
+          ..Default::default()
+        },
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(*counts.transpiled.lock().unwrap(), vec!["file:///main.ts"]);
+
+    let bytes = eszip.into_bytes_with_observer(&*counts);
+    let serialized = counts.serialized.lock().unwrap().clone();
+    assert_eq!(
+      serialized
+        .iter()
+        .map(|(specifier, _)| specifier.as_str())
+        .collect::<Vec<_>>(),
+      vec!["file:///main.ts", "file:///a.js"]
+    );
+    let (_, fut) = EszipV2::parse_with_options(
+      futures::io::BufReader::new(futures::io::Cursor::new(bytes.clone())),
+      ParseOptions {
+        observer: Some(counts.clone()),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    // the header announces the sources and source maps that were written
+    let data_len = serialized.iter().map(|(_, bytes)| *bytes as u64).sum();
+    assert_eq!(*counts.header.lock().unwrap(), Some((2, data_len)));
+    assert_eq!(counts.completed.load(Ordering::SeqCst), 0);
+    fut.await.unwrap();
+    assert_eq!(counts.completed.load(Ordering::SeqCst), 1);
+    assert_eq!(
+      *counts.parsed.lock().unwrap(),
+      vec!["file:///main.ts", "file:///a.js"]
+    );
+    // The modules header, the npm section, two sources and a source map.
+    assert_eq!(counts.checksums.load(Ordering::SeqCst), 5);
+    let bytes_read = counts.bytes_read.load(Ordering::SeqCst);
+    assert!(bytes_read > 0 && bytes_read < bytes.len());
+  }
+}
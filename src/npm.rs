@@ -0,0 +1,376 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Helpers for embedding npm packages in an eszip.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use deno_graph::ModuleGraph;
+use deno_npm::registry::NpmRegistryApi;
+use deno_npm::resolution::AddPkgReqsOptions;
+use deno_npm::resolution::NpmResolutionSnapshot;
+use deno_npm::resolution::SerializedNpmResolutionSnapshot;
+use deno_npm::resolution::ValidSerializedNpmResolutionSnapshot;
+use deno_npm::NpmPackageId;
+use deno_semver::npm::NpmPackageReqReference;
+use deno_semver::package::PackageReq;
+use indexmap::IndexSet;
+
+use crate::Module;
+
+/// The prefix of the specifiers of the files of npm packages stored with
+/// [`crate::EszipV2::add_npm_package_files`]. A file is stored as opaque data
+/// under `npm-files:/<package id>/<path>`, e.g.
+/// `npm-files:/chalk@5.3.0/source/index.js`.
+pub const NPM_PACKAGE_FILES_PREFIX: &str = "npm-files:/";
+
+/// Collect the package requirements of all the `npm:` specifiers that are
+/// roots or dependencies of the modules in the graph, in a stable order.
+pub fn npm_package_reqs(graph: &ModuleGraph) -> Vec<PackageReq> {
+  let mut reqs = IndexSet::new();
+  let mut add = |specifier: &deno_graph::ModuleSpecifier| {
+    if let Ok(reference) = NpmPackageReqReference::from_specifier(specifier) {
+      reqs.insert(reference.into_inner().req);
+    }
+  };
+  for root in &graph.roots {
+    add(root);
+  }
+  for module in graph.modules() {
+    if let deno_graph::Module::Js(module) = module {
+      for dependency in module.dependencies.values() {
+        if let Some(specifier) = dependency.get_code() {
+          add(specifier);
+        }
+        if let Some(specifier) = dependency.get_type() {
+          add(specifier);
+        }
+      }
+    }
+  }
+  let mut reqs = reqs.into_iter().collect::<Vec<_>>();
+  reqs.sort();
+  reqs
+}
+
+/// Resolve the `npm:` specifiers of a module graph against an npm registry
+/// and build the npm resolution snapshot to embed in the eszip with
+/// [`crate::EszipV2::add_npm_snapshot`].
+///
+/// Fetching package information is left to `api`, so the caller decides how
+/// the registry is reached and cached.
+pub async fn resolve_npm_snapshot(
+  graph: &ModuleGraph,
+  api: &impl NpmRegistryApi,
+) -> Result<ValidSerializedNpmResolutionSnapshot, anyhow::Error> {
+  let reqs = npm_package_reqs(graph);
+  let snapshot = NpmResolutionSnapshot::new(
+    SerializedNpmResolutionSnapshot::default().into_valid()?,
+  );
+  let result = snapshot
+    .add_pkg_reqs(
+      api,
+      AddPkgReqsOptions {
+        package_reqs: &reqs,
+        types_node_version_req: None,
+      },
+    )
+    .await;
+  for (req, result) in reqs.iter().zip(&result.results) {
+    if let Err(err) = result {
+      anyhow::bail!("failed to resolve npm package '{req}': {err}");
+    }
+  }
+  Ok(result.into_result()?.as_valid_serialized())
+}
+
+/// The files of an npm package, by their path relative to the root of the
+/// package, to store with [`crate::EszipV2::add_npm_package_files`].
+///
+/// Paths use `/` as separator. Empty and `.` segments are dropped, and paths
+/// with `..` segments are rejected.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualFileTree {
+  files: BTreeMap<String, Arc<[u8]>>,
+}
+
+impl VirtualFileTree {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  /// Read the files below `dir`, e.g. an extracted package tarball.
+  /// Symbolic links are followed.
+  pub fn from_dir(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+    fn visit(
+      tree: &mut VirtualFileTree,
+      dir: &Path,
+      prefix: &str,
+    ) -> std::io::Result<()> {
+      for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{prefix}{name}");
+        if std::fs::metadata(entry.path())?.is_dir() {
+          visit(tree, &entry.path(), &format!("{path}/"))?;
+        } else {
+          let contents = std::fs::read(entry.path())?;
+          tree.add_file(&path, contents).map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+          })?;
+        }
+      }
+      Ok(())
+    }
+
+    let mut tree = Self::new();
+    visit(&mut tree, dir.as_ref(), "")?;
+    Ok(tree)
+  }
+
+  /// Add a file, replacing the one with the same path.
+  pub fn add_file(
+    &mut self,
+    path: &str,
+    contents: impl Into<Arc<[u8]>>,
+  ) -> Result<(), anyhow::Error> {
+    let path = normalize_path(path)
+      .ok_or_else(|| anyhow::anyhow!("invalid package file path '{path}'"))?;
+    self.files.insert(path, contents.into());
+    Ok(())
+  }
+
+  pub fn get(&self, path: &str) -> Option<&Arc<[u8]>> {
+    self.files.get(&normalize_path(path)?)
+  }
+
+  /// The paths of the files, sorted.
+  pub fn paths(&self) -> impl Iterator<Item = &str> {
+    self.files.keys().map(String::as_str)
+  }
+
+  pub fn len(&self) -> usize {
+    self.files.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.files.is_empty()
+  }
+
+  pub(crate) fn into_files(self) -> impl Iterator<Item = (String, Arc<[u8]>)> {
+    self.files.into_iter()
+  }
+}
+
+/// Normalize a path of a package file, or `None` if it has `..` segments or
+/// no segments at all.
+fn normalize_path(path: &str) -> Option<String> {
+  let mut segments = Vec::new();
+  for segment in path.split(['/', '\\']) {
+    match segment {
+      "" | "." => {}
+      ".." => return None,
+      segment => segments.push(segment),
+    }
+  }
+  (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+/// The prefix of the specifiers of the files of the package with `id`.
+pub(crate) fn package_files_prefix(id: &NpmPackageId) -> String {
+  format!("{NPM_PACKAGE_FILES_PREFIX}{}/", id.as_serialized())
+}
+
+/// A read only view of the files of an npm package stored in an eszip, see
+/// [`crate::EszipV2::npm_package_files`]. Directories are implied by the
+/// paths of the files.
+pub struct NpmPackageFiles {
+  pub(crate) files: BTreeMap<String, Module>,
+}
+
+impl NpmPackageFiles {
+  /// The paths of the files, sorted.
+  pub fn paths(&self) -> impl Iterator<Item = &str> {
+    self.files.keys().map(String::as_str)
+  }
+
+  pub fn is_file(&self, path: &str) -> bool {
+    normalize_path(path).is_some_and(|path| self.files.contains_key(&path))
+  }
+
+  /// Whether a file is stored below `path`. The root of the package is a
+  /// directory.
+  pub fn is_dir(&self, path: &str) -> bool {
+    self.dir_prefix(path).is_some_and(|prefix| {
+      self.files.keys().any(|file| file.starts_with(&prefix))
+    })
+  }
+
+  /// The names of the files and directories directly below `path`, sorted,
+  /// or `None` if `path` is not a directory.
+  pub fn read_dir(&self, path: &str) -> Option<Vec<String>> {
+    let prefix = self.dir_prefix(path)?;
+    let mut names = self
+      .files
+      .keys()
+      .filter_map(|file| file.strip_prefix(&prefix))
+      .map(|rest| rest.split('/').next().unwrap().to_string())
+      .collect::<Vec<_>>();
+    names.dedup();
+    (!names.is_empty()).then_some(names)
+  }
+
+  /// Read the contents of the file at `path`. Contents that are still being
+  /// read from the archive are waited for, like with [`Module::source`].
+  pub async fn read(&self, path: &str) -> Option<Arc<[u8]>> {
+    self.files.get(&normalize_path(path)?)?.source().await
+  }
+
+  fn dir_prefix(&self, path: &str) -> Option<String> {
+    match normalize_path(path) {
+      Some(path) => Some(format!("{path}/")),
+      // the root of the package
+      None if !path.split(['/', '\\']).any(|s| s == "..") => {
+        Some(String::new())
+      }
+      None => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use deno_graph::source::MemoryLoader;
+  use deno_graph::source::Source;
+  use deno_graph::BuildOptions;
+  use deno_graph::GraphKind;
+  use deno_graph::ModuleSpecifier;
+  use deno_npm::registry::TestNpmRegistryApi;
+
+  use super::*;
+
+  async fn graph(content: &str) -> ModuleGraph {
+    let loader = MemoryLoader::new(
+      vec![(
+        "file:///main.ts".to_string(),
+        Source::Module {
+          specifier: "file:///main.ts".to_string(),
+          maybe_headers: None,
+          content: content.to_string(),
+        },
+      )],
+      vec![],
+    );
+    let mut graph = ModuleGraph::new(GraphKind::CodeOnly);
+    graph
+      .build(
+        vec![ModuleSpecifier::parse("file:///main.ts").unwrap()],
+        &loader,
+        BuildOptions::default(),
+      )
+      .await;
+    graph
+  }
+
+  #[tokio::test]
+  async fn resolves_npm_specifiers() {
+    let graph = graph(
+      "import 'npm:chalk@5';\nimport 'npm:chalk@^5.1';\nimport 'npm:a@1/sub';",
+    )
+    .await;
+    let api = TestNpmRegistryApi::default();
+    api.ensure_package_version("chalk", "5.0.0");
+    api.ensure_package_version("chalk", "5.3.0");
+    api.ensure_package_version("a", "1.0.0");
+    api.ensure_package_version("b", "2.1.0");
+    api.add_dependency(("a", "1.0.0"), ("b", "^2"));
+
+    assert_eq!(
+      npm_package_reqs(&graph)
+        .iter()
+        .map(|req| req.to_string())
+        .collect::<Vec<_>>(),
+      vec!["a@1", "chalk@^5.1", "chalk@5"]
+    );
+
+    let snapshot = resolve_npm_snapshot(&graph, &api).await.unwrap();
+    let snapshot = snapshot.as_serialized();
+    let mut root_packages = snapshot
+      .root_packages
+      .iter()
+      .map(|(req, id)| (req.to_string(), id.as_serialized()))
+      .collect::<Vec<_>>();
+    root_packages.sort();
+    assert_eq!(
+      root_packages,
+      vec![
+        ("a@1".to_string(), "a@1.0.0".to_string()),
+        ("chalk@5".to_string(), "chalk@5.3.0".to_string()),
+        ("chalk@^5.1".to_string(), "chalk@5.3.0".to_string()),
+      ]
+    );
+    assert_eq!(snapshot.packages.len(), 3);
+  }
+
+  #[tokio::test]
+  async fn npm_package_files() {
+    let id = NpmPackageId::from_serialized("@scope/pkg@1.0.0").unwrap();
+    let other =
+      NpmPackageId::from_serialized("@scope/pkg@1.0.0_a@1.0.0").unwrap();
+    let mut files = VirtualFileTree::new();
+    files.add_file("package.json", b"{}".to_vec()).unwrap();
+    files.add_file("./lib/index.js", b"1".to_vec()).unwrap();
+    files.add_file("lib\\util/a.js", b"2".to_vec()).unwrap();
+    assert!(files.add_file("../escape.js", b"".to_vec()).is_err());
+    assert_eq!(
+      files.paths().collect::<Vec<_>>(),
+      vec!["lib/index.js", "lib/util/a.js", "package.json"]
+    );
+
+    let mut eszip = crate::EszipV2::default();
+    eszip.add_npm_package_files(&id, files);
+    let mut other_files = VirtualFileTree::new();
+    other_files.add_file("other.js", b"3".to_vec()).unwrap();
+    eszip.add_npm_package_files(&other, other_files);
+    let bytes = eszip.into_bytes();
+    let (eszip, loader) =
+      crate::EszipV2::parse(futures::io::BufReader::new(bytes.as_slice()))
+        .await
+        .unwrap();
+    loader.await.unwrap();
+
+    let package = eszip.npm_package_files(&id).unwrap();
+    assert_eq!(
+      package.paths().collect::<Vec<_>>(),
+      vec!["lib/index.js", "lib/util/a.js", "package.json"]
+    );
+    assert!(package.is_file("/lib/index.js"));
+    assert!(package.is_dir("lib") && package.is_dir(""));
+    assert!(!package.is_dir("lib/index.js"));
+    assert_eq!(
+      package.read_dir("").unwrap(),
+      vec!["lib".to_string(), "package.json".to_string()]
+    );
+    assert_eq!(
+      package.read_dir("lib").unwrap(),
+      vec!["index.js".to_string(), "util".to_string()]
+    );
+    assert_eq!(package.read_dir("missing"), None);
+    assert_eq!(&*package.read("lib/util/a.js").await.unwrap(), b"2");
+    assert!(package.read("other.js").await.is_none());
+
+    let other = eszip.npm_package_files(&other).unwrap();
+    assert_eq!(other.paths().collect::<Vec<_>>(), vec!["other.js"]);
+    let missing = NpmPackageId::from_serialized("missing@1.0.0").unwrap();
+    assert!(eszip.npm_package_files(&missing).is_none());
+  }
+
+  #[tokio::test]
+  async fn fails_for_unknown_package() {
+    let graph = graph("import 'npm:missing@1';").await;
+    let api = TestNpmRegistryApi::default();
+    let err = resolve_npm_snapshot(&graph, &api).await.unwrap_err();
+    assert!(err.to_string().contains("'missing@1'"), "{err}");
+  }
+}
@@ -0,0 +1,88 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Ed25519 signatures of eszip v2 archives.
+//!
+//! A signed archive ends with a trailer made of [`SIGNATURE_MAGIC`] and the
+//! 64 byte signature of all the bytes that precede the trailer. Readers that do
+//! not know about signatures stop after the source maps section, so they
+//! ignore the trailer.
+
+use std::fmt;
+
+use ring::signature::Ed25519KeyPair;
+use ring::signature::KeyPair;
+use ring::signature::UnparsedPublicKey;
+use ring::signature::ED25519;
+
+use crate::error::ParseError;
+
+/// Marks the start of the signature trailer of an archive.
+pub const SIGNATURE_MAGIC: &[u8; 8] = b"ESZIPSIG";
+
+const SIGNATURE_LEN: usize = 64;
+
+/// An Ed25519 key to sign archives with, see [`crate::EszipV2::sign`].
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+  /// Load a key from a PKCS#8 v1 or v2 document, as generated by e.g.
+  /// `openssl genpkey -algorithm ed25519 -outform der`.
+  pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, anyhow::Error> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+      .map(Self)
+      .map_err(|err| anyhow::anyhow!("invalid ed25519 key: {err}"))
+  }
+
+  /// Derive a key from a 32 byte secret seed.
+  pub fn from_seed(seed: &[u8; 32]) -> Self {
+    Self(
+      Ed25519KeyPair::from_seed_unchecked(seed)
+        .expect("seeds of 32 bytes are always valid"),
+    )
+  }
+
+  /// The public key that verifies the signatures made with this key, to pass
+  /// to [`crate::EszipV2::parse_verified`].
+  pub fn public_key(&self) -> [u8; 32] {
+    let mut public_key = [0; 32];
+    public_key.copy_from_slice(self.0.public_key().as_ref());
+    public_key
+  }
+}
+
+impl fmt::Debug for SigningKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // never print the secret part of the key
+    f.debug_struct("SigningKey")
+      .field("public_key", &self.public_key())
+      .finish()
+  }
+}
+
+/// Append the signature trailer of `bytes` to them.
+pub(crate) fn append_signature(bytes: &mut Vec<u8>, signing_key: &SigningKey) {
+  let signature = signing_key.0.sign(bytes);
+  bytes.extend_from_slice(SIGNATURE_MAGIC);
+  bytes.extend_from_slice(signature.as_ref());
+}
+
+/// Verify the signature trailer at the end of `bytes` against `public_key`,
+/// and return the length of the signed bytes that precede the trailer.
+pub(crate) fn verify_signature(
+  bytes: &[u8],
+  public_key: &[u8],
+) -> Result<usize, ParseError> {
+  let trailer_len = SIGNATURE_MAGIC.len() + SIGNATURE_LEN;
+  let Some(signed_len) = bytes.len().checked_sub(trailer_len) else {
+    return Err(ParseError::MissingV2Signature);
+  };
+  let (signed, trailer) = bytes.split_at(signed_len);
+  let (magic, signature) = trailer.split_at(SIGNATURE_MAGIC.len());
+  if magic != SIGNATURE_MAGIC {
+    return Err(ParseError::MissingV2Signature);
+  }
+  UnparsedPublicKey::new(&ED25519, public_key)
+    .verify(signed, signature)
+    .map_err(|_| ParseError::InvalidV2Signature)?;
+  Ok(signed_len)
+}
@@ -0,0 +1,196 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Helpers for working with the source maps stored in an eszip.
+
+use sourcemap::SourceMap;
+use sourcemap::SourceMapBuilder;
+
+/// Compose the source map of a transform that was applied to the source of a
+/// module over the source map that is stored for that module, so that the
+/// resulting source map maps the transformed source back to the original
+/// sources.
+///
+/// `stored` is the source map that maps the stored source of the module to the
+/// original sources, and `transform` is the source map that maps the
+/// transformed source to the stored source. An empty `stored` source map
+/// means that the stored source is the original source, so `transform` is
+/// returned as is. An empty `transform` source map leaves `stored` unchanged.
+///
+/// The resulting source map is at most as fine-grained as `stored`.
+pub fn compose_source_maps(
+  stored: &[u8],
+  transform: &[u8],
+) -> Result<Vec<u8>, anyhow::Error> {
+  if stored.is_empty() {
+    return Ok(transform.to_vec());
+  }
+  if transform.is_empty() {
+    return Ok(stored.to_vec());
+  }
+
+  let mut source_map = SourceMap::from_slice(stored)?;
+  let transform = SourceMap::from_slice(transform)?;
+  source_map.adjust_mappings(&transform);
+
+  let mut bytes = Vec::new();
+  source_map.to_writer(&mut bytes)?;
+  Ok(bytes)
+}
+
+/// Rewrite every entry of the `sources` array of a source map.
+pub fn rewrite_sources(
+  source_map: &[u8],
+  mut rewrite: impl FnMut(&str) -> String,
+) -> Result<Vec<u8>, anyhow::Error> {
+  let mut source_map = SourceMap::from_slice(source_map)?;
+  for index in 0..source_map.get_source_count() {
+    let source = source_map.get_source(index).unwrap_or_default();
+    let rewritten = rewrite(source);
+    source_map.set_source(index, &rewritten);
+  }
+
+  let mut bytes = Vec::new();
+  source_map.to_writer(&mut bytes)?;
+  Ok(bytes)
+}
+
+/// Replace the `\r\n` line endings of a source map with `\n`, both between
+/// its tokens and in its strings, like the contents of its sources
+/// (`sourcesContent`). The source map is not parsed, so its formatting is
+/// kept. Returns `None` if it has no `\r\n` line endings.
+pub(crate) fn normalize_line_endings(source_map: &[u8]) -> Option<Vec<u8>> {
+  let mut normalized = Vec::with_capacity(source_map.len());
+  let mut rest = source_map;
+  while let Some((&byte, tail)) = rest.split_first() {
+    match (byte, tail) {
+      // a `\r\n` line ending in a string
+      (b'\\', [b'r', b'\\', b'n', ..]) => {
+        normalized.extend_from_slice(br"\n");
+        rest = &tail[3..];
+      }
+      // other escapes are copied as a whole, so that an escaped backslash is
+      // not taken for the start of an escape
+      (b'\\', [escaped, ..]) => {
+        normalized.extend_from_slice(&[byte, *escaped]);
+        rest = &tail[1..];
+      }
+      (b'\r', [b'\n', ..]) => rest = tail,
+      _ => {
+        normalized.push(byte);
+        rest = tail;
+      }
+    }
+  }
+  (normalized.len() != source_map.len()).then_some(normalized)
+}
+
+/// Build the source map of a transform that inserted `lines` lines before the
+/// line `at` of the source of the module with the given specifier.
+pub(crate) fn line_offset_source_map(
+  specifier: &str,
+  source: &[u8],
+  at: u32,
+  lines: u32,
+) -> Vec<u8> {
+  let mut builder = SourceMapBuilder::new(None);
+  let line_count = source.iter().filter(|b| **b == b'\n').count() as u32 + 1;
+  for line in 0..line_count {
+    let offset = if line < at { 0 } else { lines };
+    builder.add(line + offset, 0, line, 0, Some(specifier), None, false);
+  }
+  let mut bytes = Vec::new();
+  builder
+    .into_sourcemap()
+    .to_writer(&mut bytes)
+    .expect("writing to a vec should not fail");
+  bytes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn to_bytes(source_map: SourceMap) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    source_map.to_writer(&mut bytes).unwrap();
+    bytes
+  }
+
+  #[test]
+  fn compose_points_at_original_sources() {
+    // original.ts -> stored.js
+    let mut builder = SourceMapBuilder::new(Some("stored.js"));
+    builder.add(0, 0, 2, 0, Some("file:///original.ts"), None, false);
+    builder.add(1, 0, 5, 4, Some("file:///original.ts"), None, false);
+    let stored = to_bytes(builder.into_sourcemap());
+
+    // stored.js -> final.js, prepends a banner line
+    let mut builder = SourceMapBuilder::new(Some("final.js"));
+    builder.add(1, 0, 0, 0, Some("stored.js"), None, false);
+    builder.add(2, 0, 1, 0, Some("stored.js"), None, false);
+    let transform = to_bytes(builder.into_sourcemap());
+
+    let composed = compose_source_maps(&stored, &transform).unwrap();
+    let composed = SourceMap::from_slice(&composed).unwrap();
+    let token = composed.lookup_token(2, 0).unwrap();
+    assert_eq!(token.get_source(), Some("file:///original.ts"));
+    assert_eq!(token.get_src_line(), 5);
+    assert_eq!(token.get_src_col(), 4);
+    let token = composed.lookup_token(1, 0).unwrap();
+    assert_eq!(token.get_src_line(), 2);
+  }
+
+  #[test]
+  fn rewrite_sources_of_source_map() {
+    let mut builder = SourceMapBuilder::new(None);
+    builder.add(
+      0,
+      0,
+      0,
+      0,
+      Some("file:///Users/alice/app/a.ts"),
+      None,
+      false,
+    );
+    builder.add(1, 0, 0, 0, Some("https://deno.land/x/b.ts"), None, false);
+    let map = to_bytes(builder.into_sourcemap());
+
+    let rewritten = rewrite_sources(&map, |source| {
+      source
+        .strip_prefix("file:///Users/alice/")
+        .map(|path| format!("file:///{path}"))
+        .unwrap_or_else(|| source.to_string())
+    })
+    .unwrap();
+    let rewritten = SourceMap::from_slice(&rewritten).unwrap();
+    assert_eq!(
+      rewritten.sources().collect::<Vec<_>>(),
+      vec!["file:///app/a.ts", "https://deno.land/x/b.ts"]
+    );
+    assert_eq!(
+      rewritten.lookup_token(1, 0).unwrap().get_source(),
+      Some("https://deno.land/x/b.ts")
+    );
+  }
+
+  #[test]
+  fn compose_with_empty_source_maps() {
+    let mut builder = SourceMapBuilder::new(None);
+    builder.add(0, 0, 0, 0, Some("file:///a.ts"), None, false);
+    let map = to_bytes(builder.into_sourcemap());
+
+    assert_eq!(compose_source_maps(&[], &map).unwrap(), map);
+    assert_eq!(compose_source_maps(&map, &[]).unwrap(), map);
+    assert!(compose_source_maps(&map, b"not a source map").is_err());
+  }
+
+  #[test]
+  fn normalize_line_endings_of_source_map() {
+    let map = b"{\r\n\"sourcesContent\":[\"a;\\r\\nb;\\\\r\\\\n\"]}";
+    assert_eq!(
+      normalize_line_endings(map).unwrap(),
+      b"{\n\"sourcesContent\":[\"a;\\nb;\\\\r\\\\n\"]}"
+    );
+    assert_eq!(normalize_line_endings(br#"{"sources":["a\\r"]}"#), None);
+  }
+}
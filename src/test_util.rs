@@ -0,0 +1,304 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Generators of random eszip archives, enabled with the `test-util` feature,
+//! for property testing and fuzzing code that loads eszips.
+//!
+//! The generators are seeded, so a failing case can be reproduced from the
+//! seed alone. They do not depend on a particular random number crate; use
+//! the seeds provided by the property testing framework.
+
+use std::collections::HashMap;
+
+use deno_npm::resolution::SerializedNpmResolutionSnapshot;
+use deno_npm::resolution::SerializedNpmResolutionSnapshotPackage;
+use deno_npm::NpmPackageId;
+use deno_semver::package::PackageReq;
+use url::Url;
+
+use crate::v2::Checksum;
+use crate::EszipV2;
+use crate::ModuleFlags;
+use crate::ModuleKind;
+
+/// The ways in which [`ArchiveGenerator::malformed_bytes`] breaks an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformation {
+  /// The archive is cut short.
+  Truncated,
+  /// The magic at the start of the archive is not an eszip magic.
+  BadMagic,
+  /// A checksum of the archive does not match its content.
+  BadChecksum,
+}
+
+/// Generates random but valid eszip v2 archives, and archives that were
+/// deliberately broken.
+///
+/// Generated archives use all the kinds of header entries: modules of every
+/// [`ModuleKind`], external modules, redirects, npm specifiers, npm workspace
+/// links, npm registries and module integrities and flags, with the available
+/// checksum options.
+pub struct ArchiveGenerator {
+  state: u64,
+}
+
+impl ArchiveGenerator {
+  pub fn new(seed: u64) -> Self {
+    Self { state: seed }
+  }
+
+  /// Generate a random eszip.
+  pub fn eszip(&mut self) -> EszipV2 {
+    let mut eszip = EszipV2::default();
+    let checksums = available_checksums();
+    eszip.set_checksum(checksums[self.below(checksums.len())]);
+
+    let mut specifiers = Vec::new();
+    for i in 0..self.below(8) {
+      let specifier = format!("file:///mod{i}.js");
+      match self.below(4) {
+        0 => eszip.add_opaque_data(specifier.clone(), self.bytes(64).into()),
+        1 => {
+          let url = format!("https://cdn.example.com/{}", self.next_u64());
+          let integrity = format!("{:016x}", self.next_u64()).repeat(4);
+          eszip.add_external_module(
+            ModuleKind::OpaqueData,
+            specifier.clone(),
+            Url::parse(&url).unwrap(),
+            integrity,
+          );
+        }
+        2 => eszip.add_to_front(
+          ModuleKind::Json,
+          specifier.clone(),
+          format!("{{\"value\":{}}}", self.next_u64()).into_bytes(),
+          [],
+        ),
+        _ => {
+          let source = format!("export const a = {};", self.next_u64());
+          let source_map = if self.bool() {
+            self.bytes(64)
+          } else {
+            Vec::new()
+          };
+          eszip.add_to_front(
+            ModuleKind::JavaScript,
+            specifier.clone(),
+            source.into_bytes(),
+            source_map,
+          );
+        }
+      }
+      if self.bool() {
+        let checksum = format!("{:016x}", self.next_u64()).repeat(4);
+        eszip.set_module_integrity(&specifier, checksum);
+      }
+      if self.bool() {
+        let flags = ModuleFlags::from_bits(self.next_u64() as u8);
+        eszip.set_module_flags(&specifier, flags);
+      }
+      specifiers.push(specifier);
+    }
+    if self.bool() {
+      eszip.add_import_map(
+        ModuleKind::Jsonc,
+        "file:///deno.jsonc".to_string(),
+        b"{ // comment\n\"imports\": {} }".as_slice().into(),
+      );
+    }
+    for i in 0..self.below(3) {
+      if specifiers.is_empty() {
+        break;
+      }
+      let target = specifiers[self.below(specifiers.len())].clone();
+      eszip.add_redirect(format!("file:///redirect{i}.js"), target);
+    }
+
+    if self.bool() {
+      eszip.add_npm_snapshot(self.npm_snapshot());
+    }
+    for i in 0..self.below(3) {
+      eszip.add_npm_workspace_link(
+        format!("workspace{i}"),
+        format!("file:../packages/workspace{i}"),
+      );
+    }
+    if self.bool() {
+      let url = Url::parse("https://registry.npmjs.org/").unwrap();
+      eszip.set_npm_registry_url(None, url);
+    }
+    if self.bool() {
+      let url = Url::parse("https://npm.example.com/").unwrap();
+      eszip.set_npm_registry_url(Some("@scope".to_string()), url);
+    }
+    eszip
+  }
+
+  /// Generate the bytes of a random valid eszip.
+  pub fn valid_bytes(&mut self) -> Vec<u8> {
+    self.eszip().into_bytes()
+  }
+
+  /// Generate the bytes of a random eszip that was broken in the returned
+  /// way. Parsing the bytes, including the sources, always fails.
+  pub fn malformed_bytes(&mut self) -> (Malformation, Vec<u8>) {
+    let mut eszip = self.eszip();
+    let malformation = match self.below(3) {
+      0 => Malformation::Truncated,
+      1 => Malformation::BadMagic,
+      _ => Malformation::BadChecksum,
+    };
+    match malformation {
+      Malformation::Truncated => {
+        let mut bytes = eszip.into_bytes();
+        bytes.truncate(self.below(bytes.len()));
+        (malformation, bytes)
+      }
+      Malformation::BadMagic => {
+        let mut bytes = eszip.into_bytes();
+        bytes[0] = b'X';
+        (malformation, bytes)
+      }
+      Malformation::BadChecksum => {
+        let checksums = available_checksums();
+        let checksums = &checksums[1..];
+        if checksums.is_empty() {
+          // Without a hash function there is no checksum to break.
+          let mut bytes = eszip.into_bytes();
+          bytes.truncate(self.below(bytes.len()));
+          return (Malformation::Truncated, bytes);
+        }
+        eszip.set_checksum(checksums[self.below(checksums.len())]);
+        // Make sure the archive ends with the checksum of a source map.
+        eszip.add_to_front(
+          ModuleKind::JavaScript,
+          "file:///corrupt.js".to_string(),
+          *b"export {};",
+          *b"{}",
+        );
+        let mut bytes = eszip.into_bytes();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        (malformation, bytes)
+      }
+    }
+  }
+
+  fn npm_snapshot(
+    &mut self,
+  ) -> deno_npm::resolution::ValidSerializedNpmResolutionSnapshot {
+    let mut packages: Vec<SerializedNpmResolutionSnapshotPackage> = Vec::new();
+    let mut root_packages = HashMap::new();
+    for i in 0..1 + self.below(4) {
+      let id = NpmPackageId::from_serialized(&format!(
+        "pkg{i}@1.0.{}",
+        self.below(10)
+      ))
+      .unwrap();
+      let mut dependencies = HashMap::new();
+      for package in &packages {
+        if self.bool() {
+          dependencies.insert(package.id.nv.name.clone(), package.id.clone());
+        }
+      }
+      if i == 0 || self.bool() {
+        let req = PackageReq::from_str(&format!("pkg{i}@1")).unwrap();
+        root_packages.insert(req, id.clone());
+      }
+      packages.push(SerializedNpmResolutionSnapshotPackage {
+        id,
+        dependencies,
+        system: Default::default(),
+        dist: Default::default(),
+        optional_dependencies: Default::default(),
+        bin: None,
+        scripts: Default::default(),
+        deprecated: Default::default(),
+      });
+    }
+    SerializedNpmResolutionSnapshot {
+      root_packages,
+      packages,
+    }
+    .into_valid()
+    .unwrap()
+  }
+
+  /// SplitMix64.
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+
+  fn below(&mut self, n: usize) -> usize {
+    if n == 0 {
+      0
+    } else {
+      (self.next_u64() % n as u64) as usize
+    }
+  }
+
+  fn bool(&mut self) -> bool {
+    self.next_u64() & 1 == 1
+  }
+
+  fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+    (0..self.below(max_len + 1))
+      .map(|_| self.next_u64() as u8)
+      .collect()
+  }
+}
+
+/// The checksums supported with the enabled features, starting with
+/// [`Checksum::NoChecksum`].
+fn available_checksums() -> Vec<Checksum> {
+  vec![
+    Checksum::NoChecksum,
+    #[cfg(feature = "sha256")]
+    Checksum::Sha256,
+    #[cfg(feature = "xxhash3")]
+    Checksum::XxHash3,
+  ]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn parse(bytes: Vec<u8>) -> Result<EszipV2, crate::ParseError> {
+    let reader = futures::io::BufReader::new(futures::io::Cursor::new(bytes));
+    let (eszip, loader) = EszipV2::parse(reader).await?;
+    loader.await?;
+    Ok(eszip)
+  }
+
+  #[tokio::test]
+  async fn generated_archives_parse() {
+    for seed in 0..100 {
+      let mut generator = ArchiveGenerator::new(seed);
+      let eszip = generator.eszip();
+      let specifiers = eszip.specifiers();
+      let parsed = parse(eszip.into_bytes()).await.unwrap();
+      assert_eq!(parsed.specifiers(), specifiers, "seed {seed}");
+    }
+  }
+
+  #[tokio::test]
+  async fn malformed_archives_fail_to_parse() {
+    for seed in 0..100 {
+      let mut generator = ArchiveGenerator::new(seed);
+      let (malformation, bytes) = generator.malformed_bytes();
+      assert!(parse(bytes).await.is_err(), "seed {seed}: {malformation:?}");
+    }
+  }
+
+  #[test]
+  fn deterministic() {
+    assert_eq!(
+      ArchiveGenerator::new(7).valid_bytes(),
+      ArchiveGenerator::new(7).valid_bytes()
+    );
+  }
+}
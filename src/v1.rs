@@ -9,6 +9,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use url::Url;
 
+use crate::EszipV2;
 use crate::Module;
 use crate::ModuleInner;
 use crate::ModuleKind;
@@ -43,6 +44,15 @@ impl EszipV1 {
     serde_json::to_vec(&self).unwrap()
   }
 
+  /// Convert this eszip into an [`EszipV2`] with the same modules, see
+  /// [`EszipV2::from_v1`]. Chains of redirects are resolved, so that each
+  /// redirect points to a module.
+  pub fn into_v2(self) -> EszipV2 {
+    let mut eszip = EszipV2::from_v1(self);
+    eszip.flatten_redirects();
+    eszip
+  }
+
   pub fn get_module(&self, specifier: &str) -> Option<Module> {
     let mut specifier = &Url::parse(specifier).ok()?;
     let mut visited = HashSet::new();
@@ -92,6 +102,46 @@ impl EszipV1 {
     }
   }
 
+  /// Get the source code of the module as it was loaded, before it was
+  /// transpiled.
+  pub(crate) fn get_module_original_source(
+    &self,
+    specifier: &str,
+  ) -> Option<Arc<[u8]>> {
+    self.with_module_source(specifier, |module| module.source.clone().into())
+  }
+
+  /// Get the content type the module was served with, if any.
+  pub(crate) fn get_module_content_type(
+    &self,
+    specifier: &str,
+  ) -> Option<String> {
+    self
+      .with_module_source(specifier, |module| module.content_type.clone())
+      .flatten()
+  }
+
+  /// Get the length of the source code returned by
+  /// [`Self::get_module_source`].
+  pub(crate) fn get_module_source_len(&self, specifier: &str) -> Option<usize> {
+    self.with_module_source(specifier, |module| {
+      module.transpiled.as_ref().unwrap_or(&module.source).len()
+    })
+  }
+
+  fn with_module_source<T>(
+    &self,
+    specifier: &str,
+    f: impl FnOnce(&ModuleSource) -> T,
+  ) -> Option<T> {
+    let specifier = &Url::parse(specifier).ok()?;
+    let modules = self.modules.lock().unwrap();
+    match modules.get(specifier)? {
+      ModuleInfo::Redirect(_) => panic!("Redirects should be resolved"),
+      ModuleInfo::Source(module) => Some(f(module)),
+    }
+  }
+
   /// Removes the module from the modules map and returns the source code.
   pub(crate) fn take(&self, specifier: &str) -> Option<Arc<[u8]>> {
     let specifier = &Url::parse(specifier).ok()?;
@@ -109,9 +159,40 @@ impl EszipV1 {
     }
   }
 
-  fn specifiers(&self) -> Vec<Url> {
+  /// Get all the modules and redirects in this eszip, sorted by specifier.
+  pub(crate) fn module_infos(&self) -> Vec<(Url, ModuleInfo)> {
+    let modules = self.modules.lock().unwrap();
+    let mut modules = modules
+      .iter()
+      .map(|(specifier, info)| (specifier.clone(), info.clone()))
+      .collect::<Vec<_>>();
+    modules.sort_by(|(a, _), (b, _)| a.cmp(b));
+    modules
+  }
+
+  /// Get the specifiers of all the modules and redirects in this eszip, in
+  /// sorted order.
+  pub fn specifiers(&self) -> Vec<String> {
     let modules = self.modules.lock().unwrap();
-    modules.keys().cloned().collect()
+    let mut specifiers = modules
+      .keys()
+      .map(|specifier| specifier.to_string())
+      .collect::<Vec<_>>();
+    specifiers.sort();
+    specifiers
+  }
+
+  /// Get an iterator over all the modules in this eszip archive, without
+  /// consuming it. Redirects are followed, like in
+  /// [`get_module`](Self::get_module).
+  ///
+  /// Like [`into_iter`](IntoIterator::into_iter), this iterates over a
+  /// snapshot of the specifiers taken when it is created.
+  pub fn iter(&self) -> impl Iterator<Item = (String, Module)> + '_ {
+    self.specifiers().into_iter().filter_map(|specifier| {
+      let module = self.get_module(&specifier)?;
+      Some((specifier, module))
+    })
   }
 }
 
@@ -125,16 +206,7 @@ impl IntoIterator for EszipV1 {
   type IntoIter = std::vec::IntoIter<Self::Item>;
 
   fn into_iter(self) -> Self::IntoIter {
-    let specifiers = self.specifiers();
-    let mut v = Vec::with_capacity(specifiers.len());
-    for specifier in specifiers {
-      let Some(module) = self.get_module(specifier.as_str()) else {
-        continue;
-      };
-      v.push((specifier.to_string(), module));
-    }
-
-    v.into_iter()
+    self.iter().collect::<Vec<_>>().into_iter()
   }
 }
 
@@ -189,6 +261,34 @@ mod tests {
     assert!(!text.contains("import type { ConnInfo }"));
   }
 
+  #[tokio::test]
+  async fn get_original_source_for_ts() {
+    let data = include_bytes!("./testdata/dotland.json");
+    let eszip = EszipV1::parse(data).unwrap();
+
+    let module = eszip.get_module("file:///src/worker/handler.ts").unwrap();
+    let bytes = module.original_source().await.unwrap();
+    let text = std::str::from_utf8(&bytes).unwrap();
+    assert!(text.contains("import type { ConnInfo }"));
+    assert_eq!(module.content_type(), None);
+
+    let module = eszip
+      .get_module("https://deno.land/std@0.120.0/async/delay.ts")
+      .unwrap();
+    assert_eq!(
+      module.content_type().as_deref(),
+      Some("application/typescript; charset=utf-8")
+    );
+
+    let module = eszip
+      .get_module("https://deno.land/x/fuse@v6.4.1/dist/fuse.esm.js")
+      .unwrap();
+    assert_eq!(
+      module.original_source().await.unwrap(),
+      module.source().await.unwrap()
+    );
+  }
+
   #[tokio::test]
   async fn eszipv1_iterator_yields_all_modules() {
     let data = include_bytes!("./testdata/dotland.json");
@@ -246,4 +346,74 @@ mod tests {
 
     assert_eq!(expected_modules, actual_modules);
   }
+
+  #[test]
+  fn eszipv1_iter_borrows() {
+    let data = include_bytes!("./testdata/dotland.json");
+    let eszip = EszipV1::parse(data).unwrap();
+
+    let specifiers = eszip.specifiers();
+    assert_eq!(specifiers.len(), 37);
+    assert!(specifiers.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let iterated = eszip
+      .iter()
+      .map(|(specifier, module)| {
+        assert_eq!(specifier, module.specifier);
+        specifier
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(iterated, specifiers);
+    // The eszip is still usable after iterating over it.
+    assert!(eszip.get_module("file:///src/worker/main.ts").is_some());
+  }
+
+  #[tokio::test]
+  async fn into_v2() {
+    let data = include_bytes!("./testdata/dotland.json");
+    let v1 = EszipV1::parse(data).unwrap();
+    let specifiers = v1.specifiers();
+    let mut expected = Vec::new();
+    for (specifier, module) in v1.iter() {
+      expected.push((
+        specifier,
+        module.specifier.clone(),
+        module.source().await,
+      ));
+    }
+
+    let v2 = EszipV1::parse(data).unwrap().into_v2();
+    assert_eq!(v2.specifiers(), specifiers);
+    for (specifier, target, source) in expected {
+      let module = v2.get_module(&specifier).unwrap();
+      assert_eq!(module.specifier, target);
+      assert_eq!(module.source().await, source);
+    }
+
+    // chains of redirects are resolved
+    let main = Url::parse("file:///main.js").unwrap();
+    let a = Url::parse("file:///a.js").unwrap();
+    let b = Url::parse("file:///b.js").unwrap();
+    let v1 = EszipV1::from_modules(HashMap::from([
+      (
+        main.clone(),
+        ModuleInfo::Source(ModuleSource {
+          source: "console.log(1);".into(),
+          transpiled: None,
+          content_type: None,
+          deps: vec![],
+        }),
+      ),
+      (a.clone(), ModuleInfo::Redirect(b.clone())),
+      (b.clone(), ModuleInfo::Redirect(main.clone())),
+    ]));
+    let v2 = crate::Eszip::V1(v1).upgrade();
+    assert_eq!(
+      v2.redirects().into_iter().collect::<Vec<_>>(),
+      vec![
+        (a.to_string(), main.to_string()),
+        (b.to_string(), main.to_string()),
+      ]
+    );
+  }
 }
@@ -0,0 +1,676 @@
+// Copyright 2018-2024 the Deno authors. All rights reserved. MIT license.
+
+//! Structural checks of eszip v2 archives that go beyond what is needed to
+//! parse them, e.g. to vet archives before they are deployed.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use deno_ast::MediaType;
+use deno_graph::DefaultEsParser;
+use deno_graph::DependencyDescriptor;
+use deno_graph::DynamicArgument;
+use deno_graph::ModuleSpecifier;
+use deno_graph::ParserModuleAnalyzer;
+use import_map::ImportMap;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::error::ImportMapError;
+use crate::error::ParseError;
+use crate::inspect::dump_header;
+use crate::inspect::EntryDump;
+use crate::EszipV2;
+use crate::ModuleKind;
+
+/// A problem found by [`validate`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "finding")]
+pub enum Finding {
+  /// A redirect points to a specifier that is not in the archive.
+  DanglingRedirect { specifier: String, target: String },
+  /// Following the redirects from a specifier leads back to it.
+  RedirectCycle { specifier: String },
+  /// A root passed in [`ValidateOptions::roots`] is not in the archive.
+  MissingRoot { specifier: String },
+  /// A JavaScript, JSON or Wasm module that is not imported, directly or
+  /// transitively, by any of the roots.
+  UnreachableModule { specifier: String },
+  /// A dependency recorded for a module, see
+  /// [`crate::Module::dependencies`], is not in the archive.
+  UnresolvedDependency {
+    specifier: String,
+    dependency: String,
+  },
+  /// The import map can not be parsed.
+  InvalidImportMap { specifier: String, message: String },
+  /// An entry of the import map points to a module that is not in the
+  /// archive. Entries that map to a prefix point to no module at all.
+  MissingImportMapTarget { key: String, target: String },
+  /// An npm specifier refers to a package that is not in the npm section.
+  #[serde(rename_all = "camelCase")]
+  InvalidNpmPackageIndex {
+    specifier: String,
+    package_index: u32,
+  },
+  /// A dependency of an npm package refers to a package that is not in the
+  /// npm section.
+  #[serde(rename_all = "camelCase")]
+  InvalidNpmDependencyIndex {
+    package: String,
+    dependency: String,
+    package_index: u32,
+  },
+  /// A package of the npm section that no npm specifier depends on.
+  UnreferencedNpmPackage { package: String },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ValidateOptions {
+  /// The entry points of the archive. Modules that can not be reached from
+  /// them are reported as [`Finding::UnreachableModule`]. Reachability is not
+  /// checked if there are no roots.
+  pub roots: Vec<ModuleSpecifier>,
+  /// The specifier of the import map. Defaults to a JSON or JSONC module at
+  /// the front of the archive, see [`EszipV2::add_import_map`].
+  pub import_map: Option<String>,
+}
+
+/// Run all structural checks on the eszip v2 archive in `bytes`: redirects
+/// that dangle or form cycles, modules that are not reachable from the roots,
+/// import map entries that point to missing modules and the consistency of
+/// the package indices of the npm section.
+///
+/// Imports are found by statically analyzing the sources of the JavaScript
+/// modules, so modules that are only imported with dynamic imports of computed
+/// specifiers are reported as unreachable. Opaque data is never reported as
+/// unreachable.
+///
+/// Returns an error if the archive can not be parsed, unless the reason for
+/// that was found by one of the checks. The checks that depend on the sources
+/// of the modules are skipped in that case.
+pub async fn validate(
+  bytes: &[u8],
+  options: &ValidateOptions,
+) -> Result<Vec<Finding>, ParseError> {
+  let header = dump_header(bytes).await?;
+  let mut findings = Vec::new();
+
+  let mut modules = HashMap::new();
+  let mut redirects = HashMap::new();
+  for entry in &header.entries {
+    match entry {
+      EntryDump::Module {
+        specifier, kind, ..
+      }
+      | EntryDump::External {
+        specifier, kind, ..
+      } => {
+        modules.insert(specifier.as_str(), *kind);
+      }
+      EntryDump::Redirect { specifier, target } => {
+        redirects.insert(specifier.as_str(), target.as_str());
+      }
+      _ => {}
+    }
+  }
+  check_redirects(&modules, &redirects, &mut findings);
+  if let Some(npm) = &header.npm {
+    check_npm(&header.entries, npm, &mut findings);
+  }
+
+  let eszip = match parse(bytes).await {
+    Ok(eszip) => eszip,
+    Err(_) if !findings.is_empty() => return Ok(findings),
+    Err(err) => return Err(err),
+  };
+
+  let import_map_specifier = options.import_map.clone().or_else(|| {
+    header.entries.first().and_then(|entry| match entry {
+      EntryDump::Module {
+        specifier,
+        kind: ModuleKind::Json | ModuleKind::Jsonc,
+        ..
+      } => Some(specifier.clone()),
+      _ => None,
+    })
+  });
+  check_modules(
+    &eszip,
+    &mut modules,
+    &redirects,
+    import_map_specifier,
+    options,
+    &mut findings,
+  )
+  .await;
+  Ok(findings)
+}
+
+/// See [`EszipV2::integrity_check`].
+pub(crate) async fn integrity_check(
+  eszip: &EszipV2,
+  options: &ValidateOptions,
+) -> Vec<Finding> {
+  let redirects = eszip.redirects();
+  let kinds = eszip
+    .specifiers()
+    .into_iter()
+    .filter(|specifier| !redirects.contains_key(specifier))
+    .filter_map(|specifier| {
+      let module = eszip
+        .get_module(&specifier)
+        .or_else(|| eszip.get_import_map(&specifier))?;
+      Some((specifier, module.kind))
+    })
+    .collect::<Vec<_>>();
+  let mut modules = kinds
+    .iter()
+    .map(|(specifier, kind)| (specifier.as_str(), *kind))
+    .collect::<HashMap<_, _>>();
+  let redirects = redirects
+    .iter()
+    .map(|(specifier, target)| (specifier.as_str(), target.as_str()))
+    .collect::<HashMap<_, _>>();
+  let mut findings = Vec::new();
+  check_redirects(&modules, &redirects, &mut findings);
+
+  let import_map_specifier = options.import_map.clone().or_else(|| {
+    kinds
+      .first()
+      .filter(|(_, kind)| matches!(kind, ModuleKind::Json | ModuleKind::Jsonc))
+      .map(|(specifier, _)| specifier.clone())
+  });
+  check_modules(
+    eszip,
+    &mut modules,
+    &redirects,
+    import_map_specifier,
+    options,
+    &mut findings,
+  )
+  .await;
+  findings
+}
+
+/// Run the checks that need the sources of the modules: the import map, the
+/// recorded dependencies and, if there are roots, reachability.
+async fn check_modules(
+  eszip: &EszipV2,
+  modules: &mut HashMap<&str, ModuleKind>,
+  redirects: &HashMap<&str, &str>,
+  import_map_specifier: Option<String>,
+  options: &ValidateOptions,
+  findings: &mut Vec<Finding>,
+) {
+  let import_map = match &import_map_specifier {
+    Some(specifier) => match load_import_map(eszip, specifier).await {
+      Ok(import_map) => {
+        let specifiers = modules.keys().chain(redirects.keys()).copied();
+        check_import_map(&import_map, &specifiers.collect(), findings);
+        Some(import_map)
+      }
+      Err(err) => {
+        let message = match err {
+          ImportMapError::Invalid(_, message) => message,
+          err => err.to_string(),
+        };
+        findings.push(Finding::InvalidImportMap {
+          specifier: specifier.clone(),
+          message,
+        });
+        None
+      }
+    },
+    None => None,
+  };
+  check_dependencies(eszip, modules, redirects, findings);
+
+  if !options.roots.is_empty() {
+    // the import map is not imported by any module
+    if let Some(specifier) = &import_map_specifier {
+      modules.remove(specifier.as_str());
+    }
+    check_reachability(
+      eszip,
+      &options.roots,
+      import_map.as_ref(),
+      modules,
+      findings,
+    )
+    .await;
+  }
+}
+
+async fn parse(bytes: &[u8]) -> Result<EszipV2, ParseError> {
+  let (eszip, loader) = EszipV2::parse(futures::io::BufReader::new(
+    futures::io::Cursor::new(bytes),
+  ))
+  .await?;
+  loader.await?;
+  Ok(eszip)
+}
+
+fn check_redirects(
+  modules: &HashMap<&str, ModuleKind>,
+  redirects: &HashMap<&str, &str>,
+  findings: &mut Vec<Finding>,
+) {
+  let mut specifiers = redirects.keys().collect::<Vec<_>>();
+  specifiers.sort();
+  for specifier in specifiers {
+    let target = redirects[specifier];
+    let mut visited = HashSet::from([*specifier]);
+    let mut current = target;
+    loop {
+      if !visited.insert(current) {
+        findings.push(Finding::RedirectCycle {
+          specifier: specifier.to_string(),
+        });
+        break;
+      }
+      match redirects.get(current) {
+        Some(next) => current = next,
+        None => {
+          if !modules.contains_key(current) {
+            findings.push(Finding::DanglingRedirect {
+              specifier: specifier.to_string(),
+              target: target.to_string(),
+            });
+          }
+          break;
+        }
+      }
+    }
+  }
+}
+
+fn check_npm(
+  entries: &[EntryDump],
+  npm: &crate::inspect::NpmSectionDump,
+  findings: &mut Vec<Finding>,
+) {
+  let packages = &npm.packages;
+  let mut pending = VecDeque::new();
+  for entry in entries {
+    if let EntryDump::NpmSpecifier {
+      specifier,
+      package_index,
+    } = entry
+    {
+      if (*package_index as usize) < packages.len() {
+        pending.push_back(*package_index as usize);
+      } else {
+        findings.push(Finding::InvalidNpmPackageIndex {
+          specifier: specifier.clone(),
+          package_index: *package_index,
+        });
+      }
+    }
+  }
+  for package in packages {
+    for (dependency, package_index) in &package.dependencies {
+      if *package_index as usize >= packages.len() {
+        findings.push(Finding::InvalidNpmDependencyIndex {
+          package: package.id.clone(),
+          dependency: dependency.clone(),
+          package_index: *package_index,
+        });
+      }
+    }
+  }
+
+  let mut referenced = HashSet::new();
+  while let Some(index) = pending.pop_front() {
+    if !referenced.insert(index) {
+      continue;
+    }
+    for package_index in packages[index].dependencies.values() {
+      if (*package_index as usize) < packages.len() {
+        pending.push_back(*package_index as usize);
+      }
+    }
+  }
+  for (index, package) in packages.iter().enumerate() {
+    if !referenced.contains(&index) {
+      findings.push(Finding::UnreferencedNpmPackage {
+        package: package.id.clone(),
+      });
+    }
+  }
+}
+
+/// See [`EszipV2::parse_import_map`].
+pub(crate) async fn parse_import_map(
+  eszip: &EszipV2,
+  specifier: &str,
+) -> Result<ImportMap, ImportMapError> {
+  let import_map = load_import_map(eszip, specifier).await?;
+  let specifiers = eszip.specifiers();
+  let mut findings = Vec::new();
+  check_import_map(
+    &import_map,
+    &specifiers.iter().map(String::as_str).collect(),
+    &mut findings,
+  );
+  match findings.into_iter().next() {
+    Some(Finding::MissingImportMapTarget { key, target }) => {
+      Err(ImportMapError::MissingTarget { key, target })
+    }
+    _ => Ok(import_map),
+  }
+}
+
+/// Parse the JSON or JSONC import map with `specifier`, relative to the
+/// specifier it is stored under after following redirects.
+pub(crate) async fn load_import_map(
+  eszip: &EszipV2,
+  specifier: &str,
+) -> Result<ImportMap, ImportMapError> {
+  let invalid = |err: &dyn std::fmt::Display| {
+    ImportMapError::Invalid(specifier.to_string(), err.to_string())
+  };
+  let module = eszip
+    .get_import_map(specifier)
+    .ok_or_else(|| ImportMapError::NotFound(specifier.to_string()))?;
+  let source = module
+    .source()
+    .await
+    .ok_or_else(|| ImportMapError::SourceUnavailable(specifier.to_string()))?;
+  let text = std::str::from_utf8(&source).map_err(|err| invalid(&err))?;
+  let value = jsonc_parser::parse_to_serde_value(text, &Default::default())
+    .map_err(|err| invalid(&err))?
+    .unwrap_or_default();
+  let base_url =
+    ModuleSpecifier::parse(&module.specifier).map_err(|err| invalid(&err))?;
+  import_map::parse_from_value(base_url, value)
+    .map(|import_map| import_map.import_map)
+    .map_err(|err| invalid(&err))
+}
+
+/// Report the entries of `import_map` that point to none of `specifiers`.
+fn check_import_map(
+  import_map: &ImportMap,
+  specifiers: &HashSet<&str>,
+  findings: &mut Vec<Finding>,
+) {
+  let scoped = import_map
+    .scopes()
+    .flat_map(|scope| scope.imports.entries());
+  for entry in import_map.imports().entries().chain(scoped) {
+    let Some(target) = entry.value else {
+      continue;
+    };
+    // targets of other schemes, like `npm:` and `jsr:`, are resolved by the
+    // runtime
+    if !matches!(target.scheme(), "file" | "http" | "https") {
+      continue;
+    }
+    let target = target.as_str();
+    let found = if target.ends_with('/') {
+      specifiers
+        .iter()
+        .any(|specifier| specifier.starts_with(target))
+    } else {
+      specifiers.contains(target)
+    };
+    if !found {
+      findings.push(Finding::MissingImportMapTarget {
+        key: entry.raw_key.to_string(),
+        target: target.to_string(),
+      });
+    }
+  }
+}
+
+fn check_dependencies(
+  eszip: &EszipV2,
+  modules: &HashMap<&str, ModuleKind>,
+  redirects: &HashMap<&str, &str>,
+  findings: &mut Vec<Finding>,
+) {
+  let mut specifiers = modules.keys().collect::<Vec<_>>();
+  specifiers.sort();
+  for specifier in specifiers {
+    let Some(dependencies) = eszip
+      .get_module(specifier)
+      .and_then(|module| module.dependencies())
+    else {
+      continue;
+    };
+    for dependency in dependencies {
+      // dependencies of other schemes, like `npm:` and `node:`, are resolved
+      // by the runtime
+      let local = ModuleSpecifier::parse(&dependency.specifier)
+        .map(|url| matches!(url.scheme(), "file" | "http" | "https"))
+        // relative specifiers of archives built with a relative file base
+        .unwrap_or(true);
+      if local
+        && !modules.contains_key(dependency.specifier.as_str())
+        && !redirects.contains_key(dependency.specifier.as_str())
+      {
+        findings.push(Finding::UnresolvedDependency {
+          specifier: specifier.to_string(),
+          dependency: dependency.specifier,
+        });
+      }
+    }
+  }
+}
+
+async fn check_reachability(
+  eszip: &EszipV2,
+  roots: &[ModuleSpecifier],
+  import_map: Option<&ImportMap>,
+  modules: &HashMap<&str, ModuleKind>,
+  findings: &mut Vec<Finding>,
+) {
+  let analyzer = ParserModuleAnalyzer::new(&DefaultEsParser);
+  let mut reached = HashSet::new();
+  let mut pending = VecDeque::new();
+  for root in roots {
+    match eszip.get_module(root.as_str()) {
+      Some(module) => pending.push_back(module),
+      None => findings.push(Finding::MissingRoot {
+        specifier: root.to_string(),
+      }),
+    }
+  }
+
+  while let Some(module) = pending.pop_front() {
+    if !reached.insert(module.specifier.clone())
+      || module.kind != ModuleKind::JavaScript
+    {
+      continue;
+    }
+    let (Ok(referrer), Some(source)) = (
+      ModuleSpecifier::parse(&module.specifier),
+      module.source().await,
+    ) else {
+      continue;
+    };
+    let Ok(source) = std::str::from_utf8(&source) else {
+      continue;
+    };
+    let Ok(info) =
+      analyzer.analyze_sync(&referrer, source.into(), MediaType::JavaScript)
+    else {
+      continue;
+    };
+    for dependency in info.dependencies {
+      let specifier = match dependency {
+        DependencyDescriptor::Static(dependency) => dependency.specifier,
+        DependencyDescriptor::Dynamic(dependency) => {
+          match dependency.argument {
+            DynamicArgument::String(specifier) => specifier,
+            _ => continue,
+          }
+        }
+      };
+      let resolved = match import_map {
+        Some(import_map) => import_map.resolve(&specifier, &referrer).ok(),
+        None => deno_graph::resolve_import(&specifier, &referrer).ok(),
+      };
+      if let Some(module) =
+        resolved.and_then(|resolved| eszip.get_module(resolved.as_str()))
+      {
+        pending.push_back(module);
+      }
+    }
+  }
+
+  let mut unreachable = modules
+    .iter()
+    .filter(|(specifier, kind)| {
+      matches!(
+        kind,
+        ModuleKind::JavaScript | ModuleKind::Json | ModuleKind::Wasm
+      ) && !reached.contains(**specifier)
+    })
+    .map(|(specifier, _)| specifier.to_string())
+    .collect::<Vec<_>>();
+  unreachable.sort();
+  findings.extend(
+    unreachable
+      .into_iter()
+      .map(|specifier| Finding::UnreachableModule { specifier }),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn validate_finds_structural_problems() {
+    let mut eszip = EszipV2::default();
+    for (specifier, source) in [
+      ("file:///unused.js", "export {};"),
+      ("file:///dep.js", "export {};"),
+      ("file:///main.js", "import 'dep';\nimport('./lazy.js');"),
+      ("file:///lazy.js", "export {};"),
+    ] {
+      eszip.add_to_front(
+        ModuleKind::JavaScript,
+        specifier.to_string(),
+        source.as_bytes().to_vec(),
+        [],
+      );
+    }
+    eszip.add_opaque_data("file:///data".to_string(), Arc::new([1]));
+    eszip.add_redirect("file:///a.js".to_string(), "file:///b.js".to_string());
+    eszip.add_redirect("file:///b.js".to_string(), "file:///a.js".to_string());
+    eszip.add_redirect(
+      "file:///gone.js".to_string(),
+      "file:///missing.js".to_string(),
+    );
+    eszip.add_import_map(
+      ModuleKind::Json,
+      "file:///import_map.json".to_string(),
+      Arc::from(
+        *br#"{"imports": {"dep": "./dep.js", "gone": "./missing.js"}}"#,
+      ),
+    );
+    let bytes = eszip.into_bytes();
+
+    let options = ValidateOptions {
+      roots: vec![ModuleSpecifier::parse("file:///main.js").unwrap()],
+      import_map: None,
+    };
+    let findings = validate(&bytes, &options).await.unwrap();
+    assert_eq!(
+      serde_json::to_value(&findings).unwrap(),
+      serde_json::json!([
+        { "finding": "redirectCycle", "specifier": "file:///a.js" },
+        { "finding": "redirectCycle", "specifier": "file:///b.js" },
+        {
+          "finding": "danglingRedirect",
+          "specifier": "file:///gone.js",
+          "target": "file:///missing.js",
+        },
+        {
+          "finding": "missingImportMapTarget",
+          "key": "gone",
+          "target": "file:///missing.js",
+        },
+        { "finding": "unreachableModule", "specifier": "file:///unused.js" },
+      ])
+    );
+
+    // without roots, reachability is not checked
+    let findings = validate(&bytes, &Default::default()).await.unwrap();
+    assert_eq!(findings.len(), 4);
+  }
+
+  #[tokio::test]
+  async fn validate_npm_section() {
+    use deno_npm::resolution::SerializedNpmResolutionSnapshot;
+    use deno_npm::resolution::SerializedNpmResolutionSnapshotPackage;
+    use deno_npm::NpmPackageId;
+    use deno_semver::package::PackageReq;
+
+    let package = |id: &str| SerializedNpmResolutionSnapshotPackage {
+      id: NpmPackageId::from_serialized(id).unwrap(),
+      dependencies: Default::default(),
+      system: Default::default(),
+      dist: Default::default(),
+      optional_dependencies: Default::default(),
+      bin: None,
+      scripts: Default::default(),
+      deprecated: Default::default(),
+    };
+    let mut eszip = EszipV2::default();
+    eszip.add_npm_snapshot(
+      SerializedNpmResolutionSnapshot {
+        root_packages: [(
+          PackageReq::from_str("a@1").unwrap(),
+          NpmPackageId::from_serialized("a@1.0.0").unwrap(),
+        )]
+        .into(),
+        packages: vec![package("a@1.0.0"), package("b@1.0.0")],
+      }
+      .into_valid()
+      .unwrap(),
+    );
+    let mut bytes = eszip.into_bytes();
+    let findings = validate(&bytes, &Default::default()).await.unwrap();
+    assert_eq!(
+      findings,
+      vec![Finding::UnreferencedNpmPackage {
+        package: "b@1.0.0".to_string()
+      }]
+    );
+
+    // point the npm specifier past the packages; the archive is not
+    // checksummed
+    let entry = [&3u32.to_be_bytes()[..], b"a@1", &[2]].concat();
+    let position = bytes
+      .windows(entry.len())
+      .position(|window| window == entry)
+      .unwrap();
+    let index = position + entry.len();
+    bytes[index..index + 4].copy_from_slice(&7u32.to_be_bytes());
+    let findings = validate(&bytes, &Default::default()).await.unwrap();
+    assert_eq!(
+      findings,
+      vec![
+        Finding::InvalidNpmPackageIndex {
+          specifier: "a@1".to_string(),
+          package_index: 7,
+        },
+        Finding::UnreferencedNpmPackage {
+          package: "a@1.0.0".to_string()
+        },
+        Finding::UnreferencedNpmPackage {
+          package: "b@1.0.0".to_string()
+        },
+      ]
+    );
+  }
+}